@@ -1,12 +1,17 @@
 use std::cell::RefCell;
+use std::mem;
 use std::rc::Rc;
-use std::fs::File;
+use std::sync::Mutex;
+use std::fs::{self, File};
 use std::io::prelude::*;
 use std::env;
-use std::path::Path;
-use std::iter::FromIterator;
+use std::path::{Path, PathBuf};
+use std::process::{self, Stdio};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 
 use syntex_syntax::ast;
+use syntex_syntax::attr;
 use syntex_syntax::ext::base::{
     MacroLoader,
     MacResult,
@@ -18,12 +23,16 @@ use syntex_syntax::ext::base::{
     SyntaxExtension,
 };
 use syntex_syntax::util::small_vector::SmallVector;
-use syntex_syntax::codemap::{Span, FileLines, SpanSnippetError};
+use syntex_syntax::codemap::{self, Span, FileLines, SpanSnippetError};
+use syntex_syntax::fold::{self, Folder};
+use syntex_syntax::ptr::P;
 use syntex_syntax::parse::{self, token};
+use syntex_syntax::print::pprust;
 use syntex_syntax::parse::token::{Token, keywords};
 use syntex_syntax::ext::expand;
 use syntex_syntax::feature_gate;
 use syntex_syntax::parse::{PResult, parser, common};
+use syntex_syntax::parse::lexer::comments;
 use syntex_syntax::tokenstream::TokenTree;
 
 use cc;
@@ -53,9 +62,195 @@ typedef u8 bool_;
 
 typedef uint32_t char_;
 
+#define RUST_TYPES_ASSERT(name, condition) typedef char rust_types_assert_##name[(condition) ? 1 : -1]
+
+RUST_TYPES_ASSERT(usize_is_pointer_sized, sizeof(usize) == sizeof(void *));
+RUST_TYPES_ASSERT(isize_is_pointer_sized, sizeof(isize) == sizeof(void *));
+RUST_TYPES_ASSERT(f32_is_32_bits_wide, sizeof(f32) == 4);
+RUST_TYPES_ASSERT(f64_is_64_bits_wide, sizeof(f64) == 8);
+"#;
+
+// The C compiler's pointers must be as wide as rust's, or usize and isize
+// will silently disagree
+fn rust_types_header() -> String {
+    let mut header = String::from(RUST_TYPES_HEADER);
+    if let Ok(width) = env::var("CARGO_CFG_TARGET_POINTER_WIDTH") {
+        header.push_str(&format!(
+            "RUST_TYPES_ASSERT(usize_is_{}_bits_wide, sizeof(usize) * 8 == {});\n",
+            width, width));
+    }
+    header.push_str("\n#endif\n");
+    header
+}
+
+// Included at the top of the generated code. Where C++ needs a pointer cast,
+// the overloads of rust_c_chars pick the narrowest one which is correct for
+// the pointer they are given: none for chars, static_cast from void, and
+// reinterpret_cast only for the other byte types, which may alias chars.
+const PRELUDE: &'static str = r#"#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#if defined(__GNUC__)
+#define RUST_C_NORETURN __attribute__((noreturn))
+#elif defined(_MSC_VER)
+#define RUST_C_NORETURN __declspec(noreturn)
+#else
+#define RUST_C_NORETURN
+#endif
+
+#if defined(__cplusplus) && __cplusplus >= 201103L
+#define RUST_C_NOEXCEPT noexcept
+#else
+#define RUST_C_NOEXCEPT
+#endif
+
+#if defined(__cplusplus) && defined(__GNUC__)
+#define RUST_C_ALWAYS_INLINE __attribute__((always_inline, used)) inline
+#elif defined(__GNUC__)
+#define RUST_C_ALWAYS_INLINE __attribute__((always_inline)) __inline__
+#elif defined(_MSC_VER) && !defined(__cplusplus)
+#define RUST_C_ALWAYS_INLINE __forceinline
+#else
+#define RUST_C_ALWAYS_INLINE
+#endif
+
+#if defined(__cplusplus)
+#define RUST_C_BOOL bool
+#else
+#define RUST_C_BOOL _Bool
+#endif
+
+#if defined(__cplusplus) || (defined(__STDC_VERSION__) && __STDC_VERSION__ >= 199901L)
+#define RUST_C_INLINE static inline
+#elif defined(__GNUC__)
+#define RUST_C_INLINE static __inline__
+#elif defined(_MSC_VER)
+#define RUST_C_INLINE static __inline
+#else
+#define RUST_C_INLINE static
+#endif
+
+#if defined(__GNUC__)
+#define RUST_C_WEAK __attribute__((weak))
+#else
+#define RUST_C_WEAK
+#endif
+
+#if defined(__cplusplus) && __cplusplus >= 201103L
+#define RUST_C_ALIGNOF(type) alignof(type)
+#elif defined(__STDC_VERSION__) && __STDC_VERSION__ >= 201112L
+#define RUST_C_ALIGNOF(type) _Alignof(type)
+#elif defined(__GNUC__)
+#define RUST_C_ALIGNOF(type) __alignof__(type)
+#elif defined(_MSC_VER)
+#define RUST_C_ALIGNOF(type) __alignof(type)
+#else
+#define RUST_C_ALIGNOF(type) offsetof(struct { char c; type t; }, t)
+#endif
+#define RUST_C_ALIGN_UP(n, align) (((n) + (align) - 1) / (align) * (align))
+
+#ifndef RUST_C_ASSERT_MESSAGE
+#define RUST_C_ASSERT_MESSAGE(name) "rust-c: " #name
+#endif
+
+#if defined(__cplusplus) && (__cplusplus >= 201103L || defined(_MSC_VER))
+#define RUST_C_ASSERT(name, condition) static_assert(condition, RUST_C_ASSERT_MESSAGE(name))
+#elif defined(__STDC_VERSION__) && __STDC_VERSION__ >= 201112L
+#define RUST_C_ASSERT(name, condition) _Static_assert(condition, RUST_C_ASSERT_MESSAGE(name))
+#else
+#define RUST_C_ASSERT(name, condition) typedef char rust_c_assert_##name[(condition) ? 1 : -1]
+#endif
+
+typedef void (*rust_c_thunk)(void *);
+#define RUST_C_CALL(f) ((f##_call)(f))
+#define RUST_C_PUSH(v, x) ((v##_push)((v), (x)))
+
+#if defined(__cplusplus) && (__cplusplus >= 201703L || (defined(_MSVC_LANG) && _MSVC_LANG >= 201703L))
+#include <string_view>
+inline const char *rust_c_chars(const char *s) { return s; }
+inline const char *rust_c_chars(const void *s) { return static_cast<const char *>(s); }
+inline const char *rust_c_chars(const unsigned char *s) { return reinterpret_cast<const char *>(s); }
+inline const char *rust_c_chars(const signed char *s) { return reinterpret_cast<const char *>(s); }
+#define RUST_C_STRING_VIEW(s) (std::string_view(rust_c_chars(s), (s##_len)))
+#endif
+
+#define RUST_C_STRINGIFY_(x) #x
+#define RUST_C_STRINGIFY(x) RUST_C_STRINGIFY_(x)
+#define RUST_C_LOC (__FILE__ ":" RUST_C_STRINGIFY(__LINE__))
+
+#if defined(__GNUC__) && !defined(_WIN32)
+#include <sched.h>
+#define RUST_C_YIELD() sched_yield()
+#elif defined(__GNUC__) && (defined(__i386__) || defined(__x86_64__))
+#define RUST_C_YIELD() __builtin_ia32_pause()
+#elif defined(__GNUC__)
+#define RUST_C_YIELD() __asm__ __volatile__("yield")
+#elif defined(_MSC_VER) && (defined(_M_IX86) || defined(_M_X64))
+#include <intrin.h>
+#define RUST_C_YIELD() _mm_pause()
+#elif defined(_MSC_VER)
+#include <intrin.h>
+#define RUST_C_YIELD() __yield()
+#endif
+
+#if defined(__GNUC__)
+typedef int rust_c_once;
+#define RUST_C_ONCE_INIT 0
+static __inline__ void rust_c_call_once(rust_c_once *once, void (*init)(void))
+{
+    int state = 0;
+    if (__atomic_load_n(once, __ATOMIC_ACQUIRE) == 2) {
+        return;
+    }
+    if (__atomic_compare_exchange_n(once, &state, 1, 0, __ATOMIC_ACQUIRE, __ATOMIC_ACQUIRE)) {
+        init();
+        __atomic_store_n(once, 2, __ATOMIC_RELEASE);
+    } else {
+        while (__atomic_load_n(once, __ATOMIC_ACQUIRE) != 2) {
+            RUST_C_YIELD();
+        }
+    }
+}
+#elif defined(_MSC_VER)
+typedef long rust_c_once;
+#define RUST_C_ONCE_INIT 0
+static __inline void rust_c_call_once(rust_c_once *once, void (*init)(void))
+{
+    if (_InterlockedOr(once, 0) == 2) {
+        return;
+    }
+    if (_InterlockedCompareExchange(once, 1, 0) == 0) {
+        init();
+        _InterlockedExchange(once, 2);
+    } else {
+        while (_InterlockedOr(once, 0) != 2) {
+            RUST_C_YIELD();
+        }
+    }
+}
+#else
+/* Other compilers have no atomics to build rust_c_call_once from */
+#endif
+
+#ifndef NDEBUG
+#define RUST_C_DEBUG_ASSERT(condition) ((condition) ? (void)0 : abort())
+#else
+#define RUST_C_DEBUG_ASSERT(condition) ((void)0)
 #endif
 "#;
 
+static TRANSFORM: Mutex<Option<fn(String) -> String>> = Mutex::new(None);
+
+/// Register a function which is given the generated C code, and returns the
+/// code to write out and compile in its place, for example to prepend a
+/// license header. There is only one such function, shared by every thread;
+/// registering another replaces it. It is called once by each subsequent call
+/// to `build`, after every `c!` block has been parsed.
+pub fn set_transform(transform: fn(String) -> String) {
+    *TRANSFORM.lock().unwrap() = Some(transform);
+}
+
 pub fn mk_macro<F>(name: &str, extension: F) -> NamedSyntaxExtension
     where F: TTMacroExpander + 'static
 {
@@ -69,13 +264,499 @@ pub fn mk_macro<F>(name: &str, extension: F) -> NamedSyntaxExtension
 }
 
 pub fn build<P: AsRef<Path>, F>(src: P, name: &str, configure: F)
-    where F: for<'a> FnOnce(&'a mut gcc::Config)
+    where F: for<'a> FnOnce(&'a mut cc::Build)
+{
+    Config::new().build(src, name, configure);
+}
+
+/// Options for generating the C code, for when `build` isn't enough.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    header: String,
+    precompile_includes: bool,
+    target_cpu: Option<String>,
+    target_features: Vec<String>,
+    rust_target_features: bool,
+    skip_cargo_metadata: bool,
+    extension: Option<String>,
+    lto: Option<bool>,
+    coverage: Option<bool>,
+    build_info: bool,
+    whole_archive: bool,
+    links_before: Vec<String>,
+    links_after: Vec<String>,
+    terse_asserts: bool,
+    min_gcc_version: Option<(u32, u32)>,
+    min_clang_version: Option<(u32, u32)>,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config::default()
+    }
+
+    /// Add text to the very top of the generated C file, above the notice
+    /// that it was machine-generated, such as an SPDX license identifier. It
+    /// is written out as given, so it should be a C comment.
+    pub fn header(&mut self, header: &str) -> &mut Config {
+        self.header.push_str(header);
+        if !header.ends_with('\n') {
+            self.header.push('\n');
+        }
+        self
+    }
+
+    /// Compile the `#include`s of every c! block into a precompiled header,
+    /// so that heavy headers aren't parsed again each time the C code is
+    /// rebuilt. This is supported by gcc and clang; other compilers, and
+    /// any failure to precompile, fall back to parsing the headers as usual.
+    pub fn precompile_includes(&mut self, precompile: bool) -> &mut Config {
+        self.precompile_includes = precompile;
+        self
+    }
+
+    /// Compile the C code for a particular CPU, such as `"native"` or
+    /// `"haswell"`, which is passed to gcc and clang as `-march`. It is
+    /// ignored for MSVC, which has no equivalent.
+    pub fn target_cpu(&mut self, cpu: &str) -> &mut Config {
+        self.target_cpu = Some(cpu.to_string());
+        self
+    }
+
+    /// Let the C compiler use an x86 CPU feature, named as rust names it,
+    /// such as `"avx2"` or `"sse4.1"`. It is passed to gcc and clang as
+    /// `-mavx2`, and to MSVC as `/arch:AVX2` where there is such a flag.
+    /// Features are ignored when not targeting x86.
+    pub fn target_feature(&mut self, feature: &str) -> &mut Config {
+        self.target_features.push(feature.to_string());
+        self
+    }
+
+    /// Let the C compiler use the same x86 CPU features that rust is
+    /// compiling for, as given by `-C target-feature` or `-C target-cpu`.
+    pub fn rust_target_features(&mut self, inherit: bool) -> &mut Config {
+        self.rust_target_features = inherit;
+        self
+    }
+
+    /// Whether to tell cargo to link the compiled C code, which is the
+    /// default. Without it, the location of the library is printed instead,
+    /// and returned from `build`, for crates which link it themselves.
+    pub fn cargo_metadata(&mut self, cargo_metadata: bool) -> &mut Config {
+        self.skip_cargo_metadata = !cargo_metadata;
+        self
+    }
+
+    /// The extension of the generated C files, which is `c` by default. gcc
+    /// and clang go by the extension to decide which language to compile, so
+    /// they are told to compile C regardless, unless they are C++ compilers.
+    pub fn extension(&mut self, extension: &str) -> &mut Config {
+        self.extension = Some(extension.trim_start_matches('.').to_string());
+        self
+    }
+
+    /// Whether to compile the C code for cross-language LTO, so that the
+    /// linker may inline it into rust. By default, this is done when rustc is
+    /// given `-C linker-plugin-lto`. It needs clang, of a version using the
+    /// same LLVM as rustc.
+    pub fn lto(&mut self, lto: bool) -> &mut Config {
+        self.lto = Some(lto);
+        self
+    }
+
+    /// Whether to instrument the C code for source-based coverage, so that
+    /// it is included in the same reports as the rust code. By default, this
+    /// is done when rustc is given `-C instrument-coverage`. It needs clang,
+    /// whose profile runtime rustc links.
+    pub fn coverage(&mut self, coverage: bool) -> &mut Config {
+        self.coverage = Some(coverage);
+        self
+    }
+
+    /// Write out the compiler, and the arguments it is given, to
+    /// `<name>_build_info.txt` in `OUT_DIR`, which is useful when reporting
+    /// bugs. This is also done when the `RUST_C_BUILD_INFO` environment
+    /// variable is set.
+    pub fn build_info(&mut self, build_info: bool) -> &mut Config {
+        self.build_info = build_info;
+        self
+    }
+
+    /// Link the whole of the compiled C code, rather than only the parts
+    /// which rust refers to, so that nothing is dropped which is only used
+    /// for its side effects, such as a function marked
+    /// `__attribute__((constructor))`.
+    pub fn whole_archive(&mut self, whole_archive: bool) -> &mut Config {
+        self.whole_archive = whole_archive;
+        self
+    }
+
+    /// Whether the C compiler's static assertions, which check that the C
+    /// types agree with rust's, name what they check in their messages,
+    /// which is the default. The assertions are kept either way. For
+    /// messages of your own, define `RUST_C_ASSERT_MESSAGE(name)` in the
+    /// `header`, as a string literal.
+    pub fn assert_messages(&mut self, messages: bool) -> &mut Config {
+        self.terse_asserts = !messages;
+        self
+    }
+
+    /// The oldest version of gcc which can compile the C code, such as
+    /// `(4, 7)`. An older gcc stops with an error saying so, rather than
+    /// failing on whatever it doesn't support.
+    pub fn min_gcc_version(&mut self, major: u32, minor: u32) -> &mut Config {
+        self.min_gcc_version = Some((major, minor));
+        self
+    }
+
+    /// The oldest version of clang which can compile the C code. Apple's
+    /// clang is checked against its own version numbers, which differ from
+    /// those of clang's releases.
+    pub fn min_clang_version(&mut self, major: u32, minor: u32) -> &mut Config {
+        self.min_clang_version = Some((major, minor));
+        self
+    }
+
+    /// Link a native library before the compiled C code, as it would be
+    /// given to cargo's `rustc-link-lib`, such as `"static=foo"`. With GNU
+    /// ld, a static library must come before the libraries it depends on.
+    pub fn link_before(&mut self, lib: &str) -> &mut Config {
+        self.links_before.push(lib.to_string());
+        self
+    }
+
+    /// Link a native library after the compiled C code, for libraries which
+    /// the C code depends on.
+    pub fn link_after(&mut self, lib: &str) -> &mut Config {
+        self.links_after.push(lib.to_string());
+        self
+    }
+
+    /// Generate and compile the C code, returning the path of the static
+    /// library it was compiled into, or `None` if `RUST_C_SYNTAX_ONLY` is
+    /// set, in which case the code is only checked.
+    pub fn build<P: AsRef<Path>, F>(&self, src: P, name: &str, configure: F) -> Option<PathBuf>
+        where F: for<'a> FnOnce(&'a mut cc::Build)
+    {
+        build_with_config(self, src.as_ref(), name, configure)
+    }
+
+    /// Generate the C code for the c! blocks in the source of a crate, as
+    /// `build` would with this configuration, without writing it out or
+    /// compiling it.
+    #[cfg(feature = "testing")]
+    pub fn generate(&self, source: &str) -> String {
+        let name = "rust_c_test";
+        let state = parse_crate(Source::Str(source), name);
+        let common = common_code(self, &state, name, None);
+
+        let mut fndecls = state.fndecls.clone();
+        fndecls.extend(state.out_of_line.iter().map(|&(_, ref fndecl, _)| fndecl.clone()));
+        normalize_line_endings(&code(&common, &state.prototypes, &fndecls))
+    }
+}
+
+fn build_with_config<F>(cfg: &Config, src: &Path, name: &str, configure: F) -> Option<PathBuf>
+    where F: for<'a> FnOnce(&'a mut cc::Build)
 {
+    check_target();
+
+    // Configure cc up front, so that a missing compiler is reported before
+    // we spend any time parsing the crate
+    let mut config = cc::Build::new();
+    // Runtime checks in C, including assert(), follow rust's debug assertions
+    if env::var_os("CARGO_CFG_DEBUG_ASSERTIONS").is_none() {
+        config.define("NDEBUG", None);
+    }
+    // We tell cargo how to link the libraries ourselves, whether or not they
+    // are rebuilt, rather than leaving it to cc
+    config.cargo_metadata(false);
+    // cc compiles position-independent code by default where it is needed,
+    // which is also fine for the position-independent executables which rustc
+    // links by default, so it is only changed to follow rustc's lead
+    let msvc = is_msvc();
+    match relocation_model() {
+        Some(ref model) if model == "static" => {
+            config.pic(false);
+        }
+        Some(ref model) if model == "pie" && !msvc => {
+            config.pic(false);
+            config.flag("-fPIE");
+        }
+        Some(ref model) if model == "pic" => {
+            config.pic(true);
+        }
+        _ => {}
+    }
+    configure(&mut config);
+    check_compiler(&config);
+    if let Some(ref extension) = cfg.extension {
+        let compiler = config.get_compiler();
+        let cxx = compiler.path().file_name()
+            .map_or(false, |name| name.to_string_lossy().contains("++"));
+        if extension != "c" && !cxx && !msvc {
+            config.flag("-x").flag("c");
+        }
+    }
+    for flag in cpu_flags(cfg) {
+        config.flag(&flag);
+    }
+    if cfg.lto.unwrap_or_else(linker_plugin_lto) {
+        if is_clang(&config) {
+            config.flag("-flto=thin");
+        } else {
+            println!("cargo:warning=rust-c can only compile C for cross-language LTO with clang, \
+                      so {} is compiled without it", name);
+        }
+    }
+    if cfg.coverage.unwrap_or_else(instrument_coverage) {
+        if is_clang(&config) {
+            config.flag("-fprofile-instr-generate");
+            config.flag("-fcoverage-mapping");
+        } else {
+            println!("cargo:warning=rust-c can only instrument C for rust's coverage with clang, \
+                      so {} is compiled without it", name);
+        }
+    }
+
+    let state = parse_crate(Source::File(src), name);
+
+    // Most likely the wrong file was given, and the crate will fail to link
+    // for want of every C function
+    if state.includes.is_empty() && state.late_includes.is_empty() &&
+        state.headers.is_empty() && state.fndecls.is_empty() &&
+        state.out_of_line.is_empty() && state.sources.is_empty() {
+        println!("cargo:warning=rust-c found no c! blocks in {}, or in the modules it declares",
+                 src.display());
+    }
+    for warning in &state.warnings {
+        println!("cargo:warning={}", warning);
+    }
+
+    // Cargo only watches what it is told to, once it is told of anything, so
+    // along with the headers which the compiler finds, it is told of every
+    // file which the C code came from. MSVC can't list the headers, so cargo
+    // is left to watch every file in the crate instead.
+    if !msvc {
+        for file in state.rust_files.iter().chain(&state.sources) {
+            println!("cargo:rerun-if-changed={}", file.display());
+        }
+        println!("cargo:rerun-if-env-changed=RUST_C_BUILD_INFO");
+        println!("cargo:rerun-if-env-changed=RUST_C_SYNTAX_ONLY");
+        println!("cargo:rerun-if-env-changed=RUST_C_VERBOSE");
+        // cc would print these itself, but its metadata is turned off
+        for var in compiler_env() {
+            println!("cargo:rerun-if-env-changed={}", var);
+        }
+    }
+
+    // Shown by `cargo build -vv`, for checking how each function was mapped
+    if env::var_os("RUST_C_VERBOSE").is_some() {
+        for function in &state.functions {
+            println!("rust-c: {}: {};", function.location, function.declaration());
+        }
+    }
+
+    let out_dir = env::var("OUT_DIR")
+        .expect("Environment Variable OUT_DIR must be set");
+    let extension = cfg.extension.as_ref().map_or("c", |e| &e[..]);
+    let file = Path::new(&out_dir).join(&format!("{}.{}", name, extension));
+    let rust_types_file = Path::new(&out_dir).join("rust_types.h");
+    let includes_file = Path::new(&out_dir).join(&format!("{}_includes.h", name));
+
+    // Generate the output code. Every file starts with the same includes and
+    // headers, and is followed by its functions.
+    {
+        let common = common_code(cfg, &state, name, Some(&includes_file));
+
+        write_code(&file, &code(&common, &state.prototypes, &state.fndecls));
+        for &(ref id, ref fndecl, _) in &state.out_of_line {
+            let file = Path::new(&out_dir).join(&format!("{}_{}.{}", name, id, extension));
+            write_code(&file, &code(&common, &state.prototypes, &[fndecl.clone()]));
+        }
+    }
+
+    // Write out the rust types file
+    write_code(&rust_types_file, &rust_types_header());
+
+    let mut files = vec![file];
+    files.extend(state.sources.iter().cloned());
+
+    if cfg.build_info || env::var_os("RUST_C_BUILD_INFO").is_some() {
+        let info = build_info(&config, &files);
+        write_code(&Path::new(&out_dir).join(&format!("{}_build_info.txt", name)), &info);
+    }
+
+    // For a quicker edit loop, the C code may only be checked, in which case
+    // there is no library to link
+    if env::var_os("RUST_C_SYNTAX_ONLY").is_some() {
+        for &(ref id, _, _) in &state.out_of_line {
+            files.push(Path::new(&out_dir).join(&format!("{}_{}.{}", name, id, extension)));
+        }
+        check_syntax(&config, &files);
+        return None
+    }
+
+    // Invoke cc to build the libraries. Each out-of-line function is built
+    // into its own, ahead of the main library, which it may call into.
+    for &(ref id, _, ref feature) in &state.out_of_line {
+        let file = Path::new(&out_dir).join(&format!("{}_{}.{}", name, id, extension));
+        let mut config = config.clone();
+        if let Some(ref feature) = *feature {
+            for flag in feature_flags(feature) {
+                config.flag(&flag);
+            }
+        }
+        compile(cfg, config, &[file], &format!("{}_{}", name, id),
+                &out_dir, &includes_file);
+    }
+
+    Some(compile(cfg, config, &files, name, &out_dir, &includes_file))
+}
+
+fn build_info(config: &cc::Build, files: &[PathBuf]) -> String {
+    let compiler = config.get_compiler();
+
+    let mut info = format!("rust-c {}\n", env!("CARGO_PKG_VERSION"));
+    info.push_str(&format!("target: {}\n", env::var("TARGET").unwrap_or_default()));
+    info.push_str(&format!("host: {}\n", env::var("HOST").unwrap_or_default()));
+    info.push_str(&format!("compiler: {}\n", compiler.path().display()));
+    info.push_str("arguments:\n");
+    for arg in compiler.args() {
+        info.push_str(&format!("    {}\n", arg.to_string_lossy()));
+    }
+    info.push_str("files:\n");
+    for file in files {
+        info.push_str(&format!("    {}\n", file.display()));
+    }
+
+    // These are read by cc, and may not show up in the arguments until it
+    // compiles
+    info.push_str("environment:\n");
+    for var in compiler_env() {
+        if let Some(value) = env::var_os(&var) {
+            info.push_str(&format!("    {}={}\n", var, value.to_string_lossy()));
+        }
+    }
+
+    info
+}
+
+// The environment variables which cc picks the compiler, its flags and the
+// archiver from
+fn compiler_env() -> Vec<String> {
+    let target = env::var("TARGET").unwrap_or_default();
+    let mut vars = Vec::new();
+    for var in &["CC", "CFLAGS", "AR", "TARGET_CC", "TARGET_CFLAGS", "TARGET_AR"] {
+        vars.push(var.to_string());
+        vars.push(format!("{}_{}", var, target));
+        vars.push(format!("{}_{}", var, target.replace("-", "_")));
+    }
+    vars
+}
+
+fn check_syntax(config: &cc::Build, files: &[PathBuf]) {
+    let compiler = config.get_compiler();
+    let msvc = is_msvc();
+
+    for file in files {
+        let output = compiler.to_command()
+            .arg(if msvc { "/Zs" } else { "-fsyntax-only" })
+            .arg(file)
+            .output()
+            .unwrap_or_else(|e| panic!("rust-c could not run the C compiler: {}", e));
+        if !output.status.success() {
+            panic!("The C code generated by rust-c does not compile:\n\n{}{}",
+                   String::from_utf8_lossy(&output.stdout),
+                   String::from_utf8_lossy(&output.stderr));
+        }
+    }
+}
+
+fn archive_path(out_dir: &str, name: &str) -> PathBuf {
+    if is_msvc() {
+        Path::new(out_dir).join(&format!("{}.lib", name))
+    } else {
+        Path::new(out_dir).join(&format!("lib{}.a", name))
+    }
+}
+
+/// A function defined by a c! block, as found by `inspect`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FunctionInfo {
+    pub name: String,
+    /// The C return type.
+    pub return_type: String,
+    /// The C parameters, as declared, including those added to return the
+    /// result through, such as `out_ok`.
+    pub parameters: Vec<String>,
+    /// Where the function is defined, as `file:line`.
+    pub location: String,
+}
+
+impl FunctionInfo {
+    /// The function's C declaration, without the trailing semicolon.
+    pub fn declaration(&self) -> String {
+        format!("{} {}({})", self.return_type, self.name, self.parameters.join(", "))
+    }
+}
+
+/// A type shared with C by a c! block, as found by `inspect`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TypeInfo {
+    pub name: String,
+    /// `"struct"`, `"enum"` or `"opaque"`.
+    pub kind: &'static str,
+    /// Where the type is defined, as `file:line`.
+    pub location: String,
+}
+
+/// Everything the c! blocks of a crate define, as found by `inspect`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Blocks {
+    pub functions: Vec<FunctionInfo>,
+    pub types: Vec<TypeInfo>,
+}
+
+/// Find the functions and types defined by the c! blocks in a crate, and
+/// the modules it declares, as `build` would, without generating any code.
+/// This is for tools built on rust-c, such as those generating bindings for
+/// other languages.
+pub fn inspect<P: AsRef<Path>>(src: P) -> Blocks {
+    let state = parse_crate(Source::File(src.as_ref()), "rust_c_inspect");
+    Blocks {
+        functions: state.functions,
+        types: state.types,
+    }
+}
+
+/// Generate the C code for the c! blocks in the source of a crate, as `build`
+/// would, without writing it out or compiling it.
+#[cfg(feature = "testing")]
+pub fn generate(source: &str) -> String {
+    Config::new().generate(source)
+}
+
+/// The warnings which `build` would print for the c! blocks in the source of
+/// a crate.
+#[cfg(feature = "testing")]
+pub fn warnings(source: &str) -> Vec<String> {
+    parse_crate(Source::Str(source), "rust_c_test").warnings
+}
+
+// Where the source of the crate comes from
+enum Source<'a> {
+    File(&'a Path),
+    #[cfg(feature = "testing")]
+    Str(&'a str),
+}
+
+// Run the syntax extension through syntex_syntax, to parse out the
+// information stored in the macro invocations
+fn parse_crate(src: Source, name: &str) -> State {
     // This must be a Rc, such that it may be referred to by the macro handler
     let state: Rc<RefCell<State>> = Default::default();
 
-    // Run the syntax extension through syntex_syntax, to parse out the
-    // information stored in the macro invocations
     {
         // Create the syntax extensions
         let syntax_exts = vec![
@@ -84,11 +765,31 @@ pub fn build<P: AsRef<Path>, F>(src: P, name: &str, configure: F)
 
         // Run the expanders on the crate
         let sess = parse::ParseSess::new();
-
-        let krate = parse::parse_crate_from_file(
-            src.as_ref(),
-            Vec::new(),
-            &sess).unwrap();
+        let config = crate_config();
+
+        let krate = match src {
+            Source::File(path) => parse::parse_crate_from_file(
+                path,
+                config.clone(),
+                &sess),
+            #[cfg(feature = "testing")]
+            Source::Str(source) => parse::parse_crate_from_source_str(
+                "lib.rs".to_owned(),
+                source.to_owned(),
+                config.clone(),
+                &sess),
+        }.unwrap();
+
+        let krate = {
+            let mut test_cfg = TestCfg {
+                config: &config,
+                codemap: sess.codemap(),
+                warnings: Vec::new(),
+            };
+            let krate = test_cfg.fold_crate(krate);
+            state.borrow_mut().warnings.extend(test_cfg.warnings);
+            krate
+        };
 
         let features = feature_gate::get_features(
             &sess.span_diagnostic,
@@ -105,173 +806,1383 @@ pub fn build<P: AsRef<Path>, F>(src: P, name: &str, configure: F)
         ecfg.features = Some(&features);
 
         let mut dml = DummyMacroLoader;
-        let mut ecx = ExtCtxt::new(&sess, Vec::new(), ecfg, &mut dml);
+        let mut ecx = ExtCtxt::new(&sess, config, ecfg, &mut dml);
 
         expand::expand_crate(&mut ecx, syntax_exts, krate);
+
+        for filemap in sess.codemap().files.borrow().iter() {
+            let path = PathBuf::from(&filemap.name);
+            if path.is_file() {
+                state.borrow_mut().rust_files.push(path);
+            }
+        }
     }
 
-    let out_dir = env::var("OUT_DIR")
-        .expect("Environment Variable OUT_DIR must be set");
-    let file = Path::new(&out_dir).join(&format!("{}.c", name));
-    let rust_types_file = Path::new(&out_dir).join("rust_types.h");
+    let state = mem::replace(&mut *state.borrow_mut(), State::default());
+    state
+}
 
-    // Generate the output code
-    {
-        let state = state.borrow();
-        let code = String::from_iter([
-            "// This code was machine-generated by rust-c; Copyright is as the code parsed to generate it\n",
-            &state.includes[..],
-            &state.headers[..],
-			
-			"\n#ifdef __cplusplus\n",
-			"extern \"C\" {\n",
-			"#endif\n",
-						
-            &state.fndecls[..],
-			
-			"\n#ifdef __cplusplus\n",
-			"}\n",
-			"#endif\n",
-			
-        ].iter().cloned());
-
-        // Write out the file
-        let mut f = File::create(&file).unwrap();
-        f.write_all(code.as_bytes()).unwrap();
+// Stops the C compiler if it is older than the versions that the C code needs.
+// clang defines __GNUC__ too, so is told apart first.
+fn compiler_version_check(cfg: &Config) -> String {
+    if cfg.min_clang_version.is_none() && cfg.min_gcc_version.is_none() {
+        return String::new()
     }
 
-    // Write out the rust types file
-    {
-        let mut f = File::create(&rust_types_file).unwrap();
-        f.write_all(RUST_TYPES_HEADER.as_bytes()).unwrap();
+    let mut check = String::new();
+    let compilers = [("clang", "defined(__clang__)", "__clang_major__", "__clang_minor__", cfg.min_clang_version),
+                     ("gcc", "defined(__GNUC__)", "__GNUC__", "__GNUC_MINOR__", cfg.min_gcc_version)];
+    for &(name, defined, major_macro, minor_macro, version) in &compilers {
+        check.push_str(if check.is_empty() { "#if " } else { "#elif " });
+        check.push_str(defined);
+        check.push('\n');
+        if let Some((major, minor)) = version {
+            check.push_str(&format!(
+                "#if {} < {} || ({} == {} && {} < {})\n\
+                 #error \"rust-c: this C code needs {} {}.{} or later\"\n\
+                 #endif\n",
+                major_macro, major, major_macro, major, minor_macro, minor, name, major, minor));
+        }
+    }
+    check.push_str("#endif\n\n");
+    check
+}
+
+// The code which every generated file starts with: the includes, followed by
+// everything declared by the c! blocks other than their functions. If the
+// includes are to be precompiled, they are written to includes_file instead.
+fn common_code(cfg: &Config, state: &State, name: &str, includes_file: Option<&Path>) -> String {
+    let mut common = cfg.header.clone();
+    common.push_str(
+        "// This code was machine-generated by rust-c; Copyright is as the code parsed to generate it\n");
+
+    let mut includes = compiler_version_check(cfg);
+    if cfg.terse_asserts {
+        includes.push_str("#define RUST_C_ASSERT_MESSAGE(name) \"\"\n");
+    }
+    includes.push_str(PRELUDE);
+    for include in &state.includes {
+        includes.push_str(include);
+    }
+
+    match includes_file {
+        Some(includes_file) if cfg.precompile_includes => {
+            // The header is guarded, as clang includes it once through the
+            // precompiled header, and then again through the #include
+            let guard = format!("RUST_C_{}_INCLUDES_H",
+                                name.to_uppercase().replace(|c: char| !c.is_alphanumeric(), "_"));
+            let includes = format!("#ifndef {}\n#define {}\n\n{}\n#endif\n",
+                                   guard, guard, includes);
+            write_code(includes_file, &includes);
+
+            common.push_str(&format!("#include \"{}_includes.h\"\n", name));
+        }
+        _ => common.push_str(&includes),
+    }
+
+    if !state.forward_decls.is_empty() {
+        common.push('\n');
+    }
+    for decl in &state.forward_decls {
+        common.push_str(decl);
+    }
+
+    for enum_ in &state.enums {
+        common.push('\n');
+        common.push_str(enum_);
+    }
+
+    for header in &state.headers {
+        common.push('\n');
+        common.push_str(header);
+    }
+
+    if !state.late_includes.is_empty() {
+        common.push('\n');
+    }
+    for include in &state.late_includes {
+        common.push_str(include);
+    }
+
+    common
+}
+
+// A complete file of generated code, given the code it starts with, and the
+// functions it defines
+fn code(common: &str, prototypes: &[String], fndecls: &[String]) -> String {
+    let mut code = String::from(common);
+    code.push_str("\n#ifdef __cplusplus\nextern \"C\" {\n#endif\n\n");
+
+    for prototype in prototypes {
+        code.push_str(prototype);
+    }
+
+    for fndecl in fndecls {
+        code.push('\n');
+        code.push_str(fndecl);
+    }
+
+    code.push_str("\n#ifdef __cplusplus\n}\n#endif\n");
+
+    match *TRANSFORM.lock().unwrap() {
+        Some(transform) => transform(code),
+        None => code,
     }
+}
 
-    // Invoke gcc to build the library.
+// Some build systems run more than one build script for a crate at once,
+// into the same OUT_DIR, so each file is written out under a name of its own
+// to this process, and then renamed into place. Another process may replace
+// it, but only with a whole file.
+fn write_code(file: &Path, code: &str) {
+    let tmp = PathBuf::from(format!("{}.{}.tmp", file.display(), process::id()));
     {
-        let mut config = gcc::Config::new();
+        let mut f = File::create(&tmp).unwrap();
+        f.write_all(normalize_line_endings(code).as_bytes()).unwrap();
+    }
+    fs::rename(&tmp, file).unwrap();
+}
+
+// Build the files into a static library, unless nothing which could affect
+// it has changed since it was last built
+fn compile(cfg: &Config,
+           mut config: cc::Build,
+           files: &[PathBuf],
+           name: &str,
+           out_dir: &str,
+           includes_file: &Path)
+           -> PathBuf {
+    for file in files {
         config.file(file);
-        configure(&mut config);
-        config.compile(&format!("lib{}.a", name));
     }
+
+    let mut dependencies = Vec::new();
+    let fingerprint = fingerprint(&config, files, name, out_dir, &mut dependencies);
+    for dependency in &dependencies {
+        if !dependency.starts_with(out_dir) {
+            println!("cargo:rerun-if-changed={}", dependency.display());
+        }
+    }
+    let fingerprint_file = Path::new(out_dir).join(&format!("{}.fingerprint", name));
+    let archive = archive_path(out_dir, name);
+
+    let mut previous = String::new();
+    let up_to_date = archive.exists() &&
+        fingerprint.is_some() &&
+        File::open(&fingerprint_file)
+            .and_then(|mut f| f.read_to_string(&mut previous))
+            .is_ok() &&
+        fingerprint.as_ref() == Some(&previous);
+
+    // The generated rust code has no #[link] attribute, so this is all that
+    // links the library. The linker is given the libraries in the order
+    // they are printed.
+    if !cfg.skip_cargo_metadata {
+        for lib in &cfg.links_before {
+            println!("cargo:rustc-link-lib={}", lib);
+        }
+        if cfg.whole_archive {
+            println!("cargo:rustc-link-lib=static:+whole-archive={}", name);
+        } else {
+            println!("cargo:rustc-link-lib=static={}", name);
+        }
+        for lib in &cfg.links_after {
+            println!("cargo:rustc-link-lib={}", lib);
+        }
+        println!("cargo:rustc-link-search=native={}", out_dir);
+    }
+
+    if !up_to_date {
+        if cfg.precompile_includes {
+            precompile(&mut config, includes_file);
+        }
+        // The objects and library are built in a directory of this process's
+        // own, and the library moved into place, so that builds of the same
+        // crate at once don't write over each other's
+        let session_dir = Path::new(out_dir).join(&format!("{}_{}", name, process::id()));
+        fs::create_dir_all(&session_dir).unwrap();
+        config.out_dir(&session_dir);
+
+        // cc would panic with its own error, which doesn't say which of the
+        // libraries failed
+        if let Err(e) = config.try_compile(name) {
+            panic!("rust-c could not compile the C code for {}: {}", name, e);
+        }
+        fs::rename(archive_path(&session_dir.to_string_lossy(), name), &archive).unwrap();
+        let _ = fs::remove_dir_all(&session_dir);
+
+        if let Some(fingerprint) = fingerprint {
+            write_code(&fingerprint_file, &fingerprint);
+        }
+    }
+
+    if cfg.skip_cargo_metadata {
+        println!("rust-c: the C code was compiled into {}", archive.display());
+    }
+    archive
 }
 
-#[derive(Debug, Default)]
-struct State {
-    includes: String,
-    headers: String,
-    fndecls: String,
+// The cfg which the crate is expanded under, so that c! blocks which rust
+// won't compile don't make it into the C code either. Cargo gives build
+// scripts the cfg of the target, but not whether the crate is being built for
+// its tests, so `test` is left out, and handled by TestCfg instead.
+fn crate_config() -> ast::CrateConfig {
+    let mut config = Vec::new();
+    for (var, value) in env::vars() {
+        if !var.starts_with("CARGO_CFG_") {
+            continue
+        }
+        let name = token::intern_and_get_ident(&var["CARGO_CFG_".len()..].to_lowercase());
+        if value.is_empty() {
+            config.push(attr::mk_word_item(name));
+        } else {
+            for value in value.split(',') {
+                config.push(attr::mk_name_value_item_str(
+                    name.clone(), token::intern_and_get_ident(value)));
+            }
+        }
+    }
+
+    // Cargo only gives the enabled features as CARGO_CFG_FEATURE from 1.80.
+    // Before then, there is a CARGO_FEATURE_ variable for each, which doesn't
+    // say whether the feature's name had a `-` or a `_` in it, so both are
+    // taken to be enabled.
+    if env::var_os("CARGO_CFG_FEATURE").is_none() {
+        let feature = token::intern_and_get_ident("feature");
+        for (var, _) in env::vars() {
+            if !var.starts_with("CARGO_FEATURE_") {
+                continue
+            }
+            let value = var["CARGO_FEATURE_".len()..].to_lowercase();
+            config.push(attr::mk_name_value_item_str(
+                feature.clone(), token::intern_and_get_ident(&value)));
+            if value.contains('_') {
+                config.push(attr::mk_name_value_item_str(
+                    feature.clone(), token::intern_and_get_ident(&value.replace('_', "-"))));
+            }
+        }
+    }
+    config
 }
 
-fn span_snippet<'s>(ec: &mut ExtCtxt<'s>, span: Span) -> PResult<'s, String> {
-    match ec.parse_sess.codemap().span_to_snippet(span) {
-        Err(SpanSnippetError::IllFormedSpan(..)) =>
-            fatal(ec, span, "Unexpected ill-formed span"),
-        Err(SpanSnippetError::DistinctSources(..)) =>
-            fatal(ec, span, "Unexpected span across distinct sources"),
-        Err(SpanSnippetError::MalformedForCodemap(..)) =>
-            fatal(ec, span, "Unexpected malformed span for codemap"),
-        Err(SpanSnippetError::SourceNotAvailable{ref filename}) =>
+// The output of the build script is shared by the crate's tests and the crate
+// itself, so a c! block is generated if rust compiles it for either. Before
+// expansion, this drops the cfg attributes which only hold for the tests.
+// A module in a file of its own which is behind one has already been passed
+// over by the parser, so any c! blocks in it can't be generated, and would
+// fail to link; that is warned about instead.
+struct TestCfg<'a> {
+    config: &'a ast::CrateConfig,
+    codemap: &'a codemap::CodeMap,
+    warnings: Vec<String>,
+}
+
+impl<'a> TestCfg<'a> {
+    fn only_for_tests(&self, attr: &ast::Attribute) -> bool {
+        if !attr.check_name("cfg") {
+            return false
+        }
+        match attr.meta().as_ref().and_then(|meta| meta.meta_item_list()) {
+            Some(list) if list.len() == 1 => match list[0].meta_item() {
+                Some(cfg) => !cfg_holds(cfg, self.config, false) && cfg_holds(cfg, self.config, true),
+                None => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+impl<'a> fold::Folder for TestCfg<'a> {
+    fn fold_item(&mut self, item: P<ast::Item>) -> SmallVector<P<ast::Item>> {
+        let item = item.map(|mut item| {
+            let before = item.attrs.len();
+            item.attrs.retain(|attr| !self.only_for_tests(attr));
+            if item.attrs.len() != before {
+                if let ast::ItemKind::Mod(ref m) = item.node {
+                    if m.items.is_empty() && m.inner == codemap::DUMMY_SP {
+                        self.warnings.push(format!(
+                            "rust-c can't read `mod {};` at {}, as it is only compiled for tests; \
+                             any c! blocks in it will fail to link, unless it is declared inline",
+                            item.ident, self.codemap.span_to_string(item.span)));
+                    }
+                }
+            }
+            item
+        });
+        fold::noop_fold_item(item, self)
+    }
+
+    fn fold_mac(&mut self, mac: ast::Mac) -> ast::Mac {
+        fold::noop_fold_mac(mac, self)
+    }
+}
+
+// Whether a cfg predicate holds for the crate's config, given whether the
+// crate is being built for its tests
+fn cfg_holds(cfg: &ast::MetaItem, config: &ast::CrateConfig, test: bool) -> bool {
+    match cfg.node {
+        ast::MetaItemKind::List(ref list) => {
+            let mut items = list.iter().filter_map(|item| item.meta_item());
+            match &*cfg.name().as_str() {
+                "any" => items.any(|item| cfg_holds(item, config, test)),
+                "all" => items.all(|item| cfg_holds(item, config, test)),
+                "not" => !items.all(|item| cfg_holds(item, config, test)),
+                _ => false,
+            }
+        }
+        ast::MetaItemKind::Word if &*cfg.name().as_str() == "test" => test,
+        _ => config.iter().any(|c| c.name() == cfg.name() && c.value_str() == cfg.value_str()),
+    }
+}
+
+// The fingerprint covers the preprocessed code, so that changes to included
+// headers are noticed, along with the compiler, the flags it is given and the
+// archiver. If the code can't be preprocessed, there is no fingerprint, and
+// the library is always rebuilt. gcc and clang also list the headers which
+// aren't system headers in a depfile, which are added to dependencies.
+fn fingerprint(config: &cc::Build,
+               files: &[PathBuf],
+               name: &str,
+               out_dir: &str,
+               dependencies: &mut Vec<PathBuf>)
+               -> Option<String> {
+    let compiler = config.get_compiler();
+    let msvc = is_msvc();
+    let depfile = Path::new(out_dir).join(&format!("{}_{}.d", name, process::id()));
+
+    let mut hasher = DefaultHasher::new();
+    compiler.path().hash(&mut hasher);
+    compiler.args().hash(&mut hasher);
+
+    // The archiver isn't part of the compiler, but may be given to cc, or
+    // picked by it from the environment
+    if let Ok(archiver) = config.try_get_archiver() {
+        archiver.get_program().hash(&mut hasher);
+        for arg in archiver.get_args() {
+            arg.hash(&mut hasher);
+        }
+    }
+
+    for file in files {
+        let mut command = compiler.to_command();
+        command.arg("-E").arg(file);
+        if !msvc {
+            command.arg("-MMD").arg("-MF").arg(&depfile);
+        }
+        let output = match command.output() {
+            Ok(output) => output,
+            Err(..) => return None,
+        };
+        if !output.status.success() {
+            return None
+        }
+        output.stdout.hash(&mut hasher);
+
+        let mut deps = String::new();
+        if !msvc && File::open(&depfile).and_then(|mut f| f.read_to_string(&mut deps)).is_ok() {
+            dependencies.extend(depfile_dependencies(&deps));
+        }
+    }
+    let _ = fs::remove_file(&depfile);
+
+    Some(format!("{:016x}", hasher.finish()))
+}
+
+// The files listed in a make-style depfile, which escapes spaces in paths
+// with a backslash and continues long rules onto the next line
+fn depfile_dependencies(depfile: &str) -> Vec<PathBuf> {
+    let text = depfile.replace("\\\r\n", " ").replace("\\\n", " ");
+    let mut dependencies = Vec::new();
+    for rule in text.lines() {
+        let deps = match rule.find(": ") {
+            Some(i) => &rule[i + 2..],
+            None => continue,
+        };
+        let mut dep = String::new();
+        let mut chars = deps.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' if chars.peek() == Some(&' ') => {
+                    dep.push(' ');
+                    chars.next();
+                }
+                ' ' | '\t' => {
+                    if !dep.is_empty() {
+                        dependencies.push(PathBuf::from(mem::replace(&mut dep, String::new())));
+                    }
+                }
+                _ => dep.push(c),
+            }
+        }
+        if !dep.is_empty() {
+            dependencies.push(PathBuf::from(dep));
+        }
+    }
+    dependencies
+}
+
+// The rust target features which gcc and clang take as -m<feature>, and the
+// /arch: flag which enables them for MSVC, if any
+const X86_FEATURES: &'static [(&'static str, Option<&'static str>)] = &[
+    ("sse", None), ("sse2", None), ("sse3", None), ("ssse3", None), ("sse4.1", None), ("sse4.2", None),
+    ("popcnt", None), ("lzcnt", None), ("bmi1", None), ("bmi2", None),
+    ("fma", None), ("f16c", None), ("aes", None), ("pclmulqdq", None),
+    ("avx", Some("AVX")), ("avx2", Some("AVX2")), ("avx512f", Some("AVX512")),
+];
+
+fn cpu_flags(cfg: &Config) -> Vec<String> {
+    let msvc = is_msvc();
+    let arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap_or_default();
+    let mut flags = Vec::new();
+
+    if let Some(ref cpu) = cfg.target_cpu {
+        if cpu == "native" && env::var("HOST").ok() != env::var("TARGET").ok() {
+            panic!("rust-c can't compile for the native CPU when cross-compiling");
+        }
+        if !msvc {
+            flags.push(format!("-march={}", cpu));
+        }
+    }
+
+    if arch != "x86" && arch != "x86_64" {
+        return flags
+    }
+
+    let mut features = cfg.target_features.clone();
+    if cfg.rust_target_features {
+        features.extend(env::var("CARGO_CFG_TARGET_FEATURE").unwrap_or_default()
+                        .split(',')
+                        .map(|feature| feature.to_string()));
+    }
+
+    // MSVC only takes the widest of its /arch: flags
+    let mut arch_flag = None;
+    for feature in &features {
+        match X86_FEATURES.iter().position(|&(name, _)| name == *feature) {
+            Some(i) if msvc => {
+                if X86_FEATURES[i].1.is_some() && arch_flag.map_or(true, |j| j < i) {
+                    arch_flag = Some(i);
+                }
+            }
+            Some(..) => {
+                let flag = format!("-m{}", feature);
+                if !flags.contains(&flag) {
+                    flags.push(flag);
+                }
+            }
+            // Features such as crt-static aren't CPU features at all
+            None if cfg.target_features.contains(feature) => {
+                panic!("rust-c doesn't know how to enable the CPU feature {} in C", feature);
+            }
+            None => {}
+        }
+    }
+    if let Some(i) = arch_flag {
+        flags.push(format!("/arch:{}", X86_FEATURES[i].1.unwrap()));
+    }
+
+    flags
+}
+
+// The flags which compile a function variant for a CPU feature. As with
+// target_feature, features are ignored when not targeting x86.
+fn feature_flags(feature: &str) -> Vec<String> {
+    let msvc = is_msvc();
+    let arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap_or_default();
+    if arch != "x86" && arch != "x86_64" {
+        return Vec::new()
+    }
+
+    match X86_FEATURES.iter().find(|&&(name, _)| name == feature) {
+        Some(&(_, Some(arch))) if msvc => vec![format!("/arch:{}", arch)],
+        Some(..) if msvc => Vec::new(),
+        Some(..) => vec![format!("-m{}", feature)],
+        None => panic!("rust-c doesn't know how to enable the CPU feature {} in C", feature),
+    }
+}
+
+// The flags given to rustc, which build scripts are only told of through the
+// environment
+fn rustflags() -> Vec<String> {
+    match env::var("CARGO_ENCODED_RUSTFLAGS") {
+        Ok(flags) => flags.split('\x1f').map(|flag| flag.to_string()).collect(),
+        Err(..) => env::var("RUSTFLAGS").unwrap_or_default()
+            .split_whitespace()
+            .map(|flag| flag.to_string())
+            .collect(),
+    }
+}
+
+// Whether rustc has been asked to do cross-language LTO, which is the only
+// kind which can see into the C code
+fn linker_plugin_lto() -> bool {
+    rustflags().iter().any(|flag| flag.contains("linker-plugin-lto"))
+}
+
+// Whether rustc has been asked to instrument the crate for source-based
+// coverage, which only clang's instrumentation can be reported alongside
+fn instrument_coverage() -> bool {
+    let off = ["=n", "=no", "=off", "=false"];
+    rustflags().iter().any(|flag| {
+        flag.contains("instrument-coverage") && !off.iter().any(|off| flag.ends_with(off))
+    })
+}
+
+// Whether the C compiler is clang, which is the only one with some flags,
+// even where it is installed as `cc`
+fn is_clang(config: &cc::Build) -> bool {
+    config.get_compiler().is_like_clang()
+}
+
+// Whether the target is built with MSVC, rather than gcc or clang
+fn is_msvc() -> bool {
+    env::var("TARGET").unwrap_or_default().contains("msvc")
+}
+
+// The relocation model given to rustc, such as "pie" or "static", if it was
+// changed from the target's default
+fn relocation_model() -> Option<String> {
+    let flags = rustflags();
+    flags.iter()
+        .zip(flags.iter().skip(1).map(Some).chain(Some(None)))
+        .filter_map(|(flag, next)| {
+            let flag = if flag == "-C" { next.map_or("", |next| &next[..]) } else { flag.trim_start_matches("-C") };
+            if flag.starts_with("relocation-model=") {
+                Some(flag["relocation-model=".len()..].to_string())
+            } else {
+                None
+            }
+        })
+        .last()
+}
+
+// gcc picks up header.h.gch by itself when header.h is included, as long as it
+// was built with the same flags, while clang must be told to use header.h.pch.
+// A failure here isn't fatal, as the header can still be included as it is.
+fn precompile(config: &mut cc::Build, header: &Path) {
+    if is_msvc() {
+        return
+    }
+    let compiler = config.get_compiler();
+    let clang = is_clang(config);
+    let output = header.with_extension(if clang { "h.pch" } else { "h.gch" });
+
+    let status = compiler.to_command()
+        .arg("-x").arg("c-header")
+        .arg(header)
+        .arg("-o").arg(&output)
+        .status();
+    match status {
+        Ok(ref status) if status.success() => {
+            if clang {
+                config.flag("-include-pch");
+                config.flag(output.to_str().unwrap());
+            }
+        }
+        _ => {
+            // gcc would otherwise go on using a stale precompiled header
+            let _ = fs::remove_file(&output);
+            println!("cargo:warning=rust-c could not precompile {}, so it will be compiled as usual",
+                     header.display());
+        }
+    }
+}
+
+// Code blocks are copied verbatim out of the source files, so a checkout with
+// CRLF line endings would otherwise leak those into the generated files. We
+// always write LF (and never a BOM), so the output is byte-identical on every
+// platform.
+fn normalize_line_endings(code: &str) -> String {
+    code.replace("\r\n", "\n")
+}
+
+// wasm32-unknown-unknown has no C library, and cc would otherwise run the
+// host's compiler for it, so unless a compiler has been picked explicitly,
+// explain what is supported rather than producing a useless native archive.
+// wasm32-unknown-emscripten needs nothing special, as cc runs emcc for it.
+fn check_target() {
+    let target = env::var("TARGET").unwrap_or_default();
+    if target != "wasm32-unknown-unknown" {
+        return
+    }
+
+    let overridden = ["CC_wasm32-unknown-unknown", "CC_wasm32_unknown_unknown", "TARGET_CC", "CC"]
+        .iter()
+        .any(|var| env::var_os(var).is_some());
+    if !overridden {
+        panic!("rust-c can not build C code for wasm32-unknown-unknown, as it has no C library. \
+                Build for wasm32-unknown-emscripten instead, or set CC_wasm32_unknown_unknown \
+                to a C compiler which targets it, such as clang");
+    }
+}
+
+// Make sure that the C compiler cc has chosen can actually be run, as
+// otherwise the failure only surfaces from deep inside cc::Build::compile
+fn check_compiler(config: &cc::Build) {
+    let compiler = match config.try_get_compiler() {
+        Ok(compiler) => compiler,
+        Err(e) => panic!("Unable to find a C compiler ({}). Install a C toolchain, \
+                          or set the CC environment variable to the path of a C compiler", e),
+    };
+    let result = compiler.to_command()
+        .arg("--version")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+
+    if let Err(e) = result {
+        panic!("Unable to run the C compiler `{}` ({}). Install a C toolchain, \
+                or set the CC environment variable to the path of a C compiler",
+               compiler.path().display(), e);
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    includes: Vec<String>,
+    // Included after the shared enums and structs, for headers which use them
+    late_includes: Vec<String>,
+    // The headers which have been included, without any whitespace
+    included: Vec<String>,
+    // Declared ahead of the headers, so that shared structs may refer to one
+    // another in any order
+    forward_decls: Vec<String>,
+    enums: Vec<String>,
+    // The size of each shared enum and struct, as a C expression
+    type_sizes: Vec<(String, String)>,
+    // The shared structs which derive PartialEq, and so have a rust_c_eq_ function
+    eq_structs: Vec<String>,
+    headers: Vec<String>,
+    prototypes: Vec<String>,
+    fndecls: Vec<String>,
+    // Functions marked #[out_of_line], each of which is compiled on its own,
+    // along with the CPU feature to compile it for
+    out_of_line: Vec<(String, String, Option<String>)>,
+    sources: Vec<PathBuf>,
+    // The rust files which were parsed, which cargo is to watch for changes
+    rust_files: Vec<PathBuf>,
+    // Printed for cargo once every block has been parsed
+    warnings: Vec<String>,
+    functions: Vec<FunctionInfo>,
+    types: Vec<TypeInfo>,
+}
+
+fn span_snippet<'s>(ec: &mut ExtCtxt<'s>, span: Span) -> PResult<'s, String> {
+    match ec.parse_sess.codemap().span_to_snippet(span) {
+        Err(SpanSnippetError::IllFormedSpan(..)) =>
+            fatal(ec, span, "Unexpected ill-formed span"),
+        Err(SpanSnippetError::DistinctSources(..)) =>
+            fatal(ec, span, "Unexpected span across distinct sources"),
+        Err(SpanSnippetError::MalformedForCodemap(..)) =>
+            fatal(ec, span, "Unexpected malformed span for codemap"),
+        Err(SpanSnippetError::SourceNotAvailable{ref filename}) =>
             fatal(ec, span, &format!("Unexpected unavaliable source: {}", filename)),
         Ok(v) => Ok(v),
     }
 }
 
-fn fatal<'s, T>(ec: &mut ExtCtxt<'s>, span: Span, msg: &str) -> PResult<'s, T> {
-    Err(ec.struct_span_fatal(span, msg))
+fn fatal<'s, T>(ec: &mut ExtCtxt<'s>, span: Span, msg: &str) -> PResult<'s, T> {
+    Err(ec.struct_span_fatal(span, msg))
+}
+
+// Where a span starts, as `file:line`
+fn location(ec: &mut ExtCtxt, span: Span) -> String {
+    match ec.parse_sess.codemap().span_to_lines(span) {
+        Ok(FileLines{ref file, ref lines}) if !lines.is_empty() =>
+            format!("{}:{}", file.name, lines[0].line_index + 1),
+        _ => String::new(),
+    }
+}
+
+fn line_pragma(ec: &mut ExtCtxt, span: Span) -> String {
+    match ec.parse_sess.codemap().span_to_lines(span) {
+        Ok(FileLines{ref file, ref lines}) if !lines.is_empty() =>
+            format!("#line {} {:?}\n", lines[0].line_index + 1, file.name),
+        _ => String::new(),
+    }
+}
+
+fn read_code_block<'s>(ec: &mut ExtCtxt<'s>,
+                       parser: &mut parser::Parser<'s>)
+                       -> PResult<'s, (Span, String)> {
+    match try!(parser.parse_token_tree()) {
+        TokenTree::Token(span, token::Literal(token::Str_(s), _)) |
+        TokenTree::Token(span, token::Literal(token::StrRaw(s, _), _)) => {
+            let s = ast::Ident::with_empty_ctxt(s).name.as_str();
+            Ok((span, s.to_string()))
+        }
+        TokenTree::Delimited(_, ref del) if del.delim == token::Brace => {
+            let span = Span {
+                lo: del.open_span.hi,
+                hi: del.close_span.lo,
+                expn_id: del.open_span.expn_id,
+            };
+
+            Ok((span, try!(span_snippet(ec, span))))
+        }
+        tt => return fatal(ec, tt.get_span(), "Unexpected token while parsing import")
+    }
+}
+
+// Strip the indentation which a code block had in the rust source, and indent
+// it by one level instead. Only leading whitespace is changed, and the first
+// line (which shares its line with the opening delimiter) is kept, so every
+// line stays where the #line pragma says it is.
+fn reindent(code: &str) -> String {
+    let mut lines = code.split('\n');
+    let first = lines.next().unwrap_or("").trim();
+    let rest: Vec<&str> = lines.map(|line| line.trim_end()).collect();
+
+    let strip = rest.iter()
+        .filter(|line| !line.is_empty())
+        .map(|line| line.chars().take_while(|c| c.is_whitespace()).count())
+        .min()
+        .unwrap_or(0);
+
+    let mut s = String::from(first);
+    for line in rest {
+        s.push('\n');
+        if !line.is_empty() {
+            s.push_str("    ");
+            s.extend(line.chars().skip(strip));
+        }
+    }
+    s
+}
+
+fn check_ident(parser: &parser::Parser, name: &str) -> bool {
+    match parser.token {
+        Token::Ident(ref i) => i.name.as_str() == name,
+        _ => false,
+    }
+}
+
+// Parse a `rust_type as "c_type"` pair, returning the C type
+fn parse_c_type<'s>(parser: &mut parser::Parser<'s>) -> PResult<'s, String> {
+    try!(parser.parse_ty());
+    try!(parser.expect_keyword(keywords::As));
+    let (cty, _) = try!(parser.parse_str());
+    Ok(cty.to_string())
+}
+
+// Parse the type following the `->` of a function, returning the C return
+// type. Any extra arguments needed to return it are added to `args`.
+fn parse_return<'s>(parser: &mut parser::Parser<'s>,
+                    id: ast::Ident,
+                    type_sizes: &[(String, String)],
+                    args: &mut Vec<String>,
+                    decls: &mut Vec<String>)
+                    -> PResult<'s, String> {
+    if check_ident(parser, "Result") {
+        // A Result is returned through a pair of out-parameters, with the
+        // function itself returning whether or not it succeeded
+        parser.bump();
+        try!(parser.expect(&Token::Lt));
+
+        // A Result<(), String> instead has the error written into a buffer,
+        // as a nul-terminated string
+        if parser.check(&token::OpenDelim(token::Paren)) &&
+            parser.look_ahead(1, |t| *t == Token::CloseDelim(token::Paren)) &&
+            parser.look_ahead(2, |t| *t == Token::Comma) {
+            parser.bump();
+            parser.bump();
+            try!(parser.expect(&Token::Comma));
+            if !check_ident(parser, "String") {
+                return Err(parser.span_fatal(
+                    parser.span, "Expected `String`, or a C type for the value of the Result"));
+            }
+            parser.bump();
+            try!(parser.expect(&Token::Gt));
+
+            args.push("char *err".to_owned());
+            args.push("size_t err_len".to_owned());
+            return Ok("RUST_C_BOOL".to_owned())
+        }
+
+        let ok_cty = try!(parse_c_type(parser));
+        try!(parser.expect(&Token::Comma));
+        let err_cty = try!(parse_c_type(parser));
+        try!(parser.expect(&Token::Gt));
+
+        args.push(format!("{} *out_ok", ok_cty));
+        args.push(format!("{} *out_err", err_cty));
+        return Ok("RUST_C_BOOL".to_owned())
+    }
+
+    if check_ident(parser, "io") && parser.look_ahead(1, |t| *t == Token::ModSep) {
+        // An io::Result<()> is returned as an errno, which is zero for success.
+        // The macro only accepts it written just so.
+        let span = parser.span;
+        let ty = try!(parser.parse_ty());
+        if pprust::ty_to_string(&ty) != "io::Result<()>" {
+            return Err(parser.span_fatal(span, &format!(
+                "`{}` can't be returned as an errno; only `io::Result<()>` can",
+                pprust::ty_to_string(&ty))));
+        }
+        return Ok("int".to_owned())
+    }
+
+    if check_ident(parser, "Vec") {
+        // A Vec is returned as a malloc'd array, with its length written to
+        // an out-parameter
+        parser.bump();
+        try!(parser.expect(&Token::Lt));
+        let cty = try!(parse_c_type(parser));
+        try!(parser.expect(&Token::Gt));
+
+        args.push("size_t *out_len".to_owned());
+        return Ok(format!("{} *", cty))
+    }
+
+    if parser.check(&token::OpenDelim(token::Bracket)) {
+        // A fixed-size array is returned by value, inside a struct, as C
+        // can't return arrays
+        let ty = try!(parser.parse_ty());
+        try!(parser.expect_keyword(keywords::As));
+        let (cty, _) = try!(parser.parse_str());
+        if let ast::TyKind::Array(_, ref len) = ty.node {
+            decls.push(format!("struct {}_result {{\n    {} data[{}];\n}};\n",
+                               id, cty, pprust::expr_to_string(len)));
+            return Ok(format!("struct {}_result", id))
+        }
+        return Ok(cty.to_string())
+    }
+
+    if parser.check(&token::OpenDelim(token::Paren)) {
+        let ty_span = parser.span;
+        let ty = try!(parser.parse_ty());
+        try!(parser.expect_keyword(keywords::As));
+
+        // A tuple is returned through an out-parameter, pointing to a struct
+        // with a field for each element, suffixed with the element's index.
+        // The c! macro only does so for tuples of these lengths.
+        if let ast::TyKind::Tup(ref elems) = ty.node {
+            if elems.len() == 1 || elems.len() > 4 {
+                return Err(parser.span_fatal(ty_span, &format!(
+                    "`{}` returns a tuple of {} elements, but only tuples of 2 to 4 elements \
+                     can be returned from C", id, elems.len())));
+            }
+            if !elems.is_empty() {
+                let span = parser.span;
+                let ctys: Vec<_> = try!(parser.parse_unspanned_seq(
+                    &token::OpenDelim(token::Paren),
+                    &token::CloseDelim(token::Paren),
+                    common::SeqSep::trailing_allowed(token::Comma),
+                    |p| p.parse_str().map(|(cty, _)| cty.to_string())));
+                if elems.len() != ctys.len() {
+                    return Err(parser.span_fatal(span, &format!(
+                        "Expected {} C types, one for each element of the tuple",
+                        elems.len())));
+                }
+
+                let mut def = format!("struct {}_result {{\n", id);
+                for (i, cty) in ctys.iter().enumerate() {
+                    def.push_str(&format!("    {} _{};\n", cty, i));
+                }
+                def.push_str("};\n");
+                decls.push(def);
+
+                args.push(format!("struct {}_result *out", id));
+                return Ok("void".to_owned())
+            }
+        }
+
+        let (cty, _) = try!(parser.parse_str());
+        return Ok(cty.to_string())
+    }
+
+    if parser.check(&Token::BinOp(token::And)) {
+        // A function filling a buffer returns how much of it was written,
+        // which the wrapper returns as a slice
+        try!(parser.parse_ty());
+        let fills = parser.eat_keyword(keywords::In);
+        if fills {
+            let span = parser.span;
+            let buf = try!(parser.parse_ident());
+            if !args.iter().any(|arg| arg.ends_with(&format!(" {}_len", buf))) {
+                return Err(parser.span_fatal(span, &format!(
+                    "`{}` is not a slice argument, which could be filled", buf)));
+            }
+        }
+        try!(parser.expect_keyword(keywords::As));
+        let span = parser.span;
+        let (cty, _) = try!(parser.parse_str());
+        // Rust reads the count as a usize
+        if fills && &*cty != "size_t" {
+            return Err(parser.span_fatal(span, &format!(
+                "A function filling a buffer returns how much of it was written as \"size_t\", not \"{}\"",
+                cty)));
+        }
+        return Ok(cty.to_string())
+    }
+
+    // A function which never returns has no C type to give
+    if parser.eat(&Token::Not) {
+        return Ok("RUST_C_NORETURN void".to_owned())
+    }
+
+    // Anything else, including a struct or enum shared by an earlier block,
+    // is returned by value, so C must return something of the same size.
+    // Floating point numbers are returned in other registers than integers,
+    // so C must return the same kind of number too.
+    let span = parser.span;
+    let ty = try!(parser.parse_ty());
+    try!(parser.expect_keyword(keywords::As));
+    let (cty, _) = try!(parser.parse_str());
+    if let (Some(kind), Some(ckind)) = (rust_number_kind(&ty), c_number_kind(&cty)) {
+        if kind != ckind {
+            return Err(parser.span_fatal(span, &format!(
+                "`{}` returns {} values, which C is given as `{}`, a {} type. These are \
+                 returned in different registers, so C must return a {} type too",
+                id, kind, cty, ckind, kind)));
+        }
+    }
+    let size = rust_size(&ty).map(String::from).or_else(|| type_size(type_sizes, &ty));
+    if let Some(size) = size {
+        if !cty.contains("void") {
+            decls.push(format!("RUST_C_ASSERT({}_returns_its_rust_type, sizeof({}) == {});\n",
+                               id, cty, size));
+        }
+    }
+    Ok(cty.to_string())
+}
+
+fn expand_include<'s>(ec: &mut ExtCtxt<'s>,
+                      parser: &mut parser::Parser<'s>,
+                      st: &mut State,
+                      _: Span,
+                      attrs: &Attrs)
+                      -> PResult<'s, ()> {
+    let (span, text) = match try!(parser.parse_token_tree()) {
+        // < foo >
+        TokenTree::Token(lo_span, Token::Lt) => {
+            let hi;
+            loop {
+                match try!(parser.parse_token_tree()) {
+                    TokenTree::Token(hi_span, Token::Gt) => {
+                        hi = hi_span.hi;
+                        break;
+                    }
+                    TokenTree::Token(span, Token::Eof) =>
+                        return fatal(ec, span, "Unexpected EOF while parsing import"),
+                    _ => continue,
+                }
+            }
+
+            let span = Span {
+                lo: lo_span.lo,
+                hi: hi,
+                expn_id: lo_span.expn_id,
+            };
+
+            (span, try!(span_snippet(ec, span)))
+        }
+
+        // "foo"
+        TokenTree::Token(span, Token::Literal(token::Lit::Str_(_), _)) =>
+            (span, try!(span_snippet(ec, span))),
+
+        tt => return fatal(ec, tt.get_span(), "Unexpected token while parsing import")
+    };
+
+    // Add the #include statement to the output, unless an earlier block has
+    // already included the same header, which may not be guarded
+    let header: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    if st.included.contains(&header) {
+        return Ok(())
+    }
+    st.included.push(header);
+    let include = format!("{}#include {}\n", line_pragma(ec, span), text);
+    if attrs.after_types {
+        st.late_includes.push(include);
+    } else {
+        st.includes.push(include);
+    }
+
+    Ok(())
+}
+
+fn expand_raw<'s>(ec: &mut ExtCtxt<'s>,
+                  parser: &mut parser::Parser<'s>,
+                  st: &mut State,
+                  _: Span)
+                  -> PResult<'s, ()> {
+    let (span, text) = try!(read_code_block(ec, parser));
+
+    for line in text.lines() {
+        let line = line.trim_start();
+        if !line.starts_with('#') {
+            continue
+        }
+        let mut words = line[1..].split_whitespace();
+        if words.next() != Some("define") {
+            continue
+        }
+        if let Some(name) = words.next() {
+            let name = name.split('(').next().unwrap();
+            if is_reserved(name) {
+                return fatal(ec, span, &format!(
+                    "`{}` is defined by rust-c, and may not be redefined in a c! block", name));
+            }
+        }
+    }
+
+    // A typedef names its type last, after the body of any struct it
+    // defines, unless it is of a function pointer, as in
+    // `typedef void (*name)(void);`
+    let mut code = String::new();
+    let mut depth = 0;
+    for line in text.lines().filter(|line| !line.trim_start().starts_with('#')) {
+        for c in line.chars() {
+            match c {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                _ if depth == 0 => code.push(c),
+                _ => {}
+            }
+        }
+        code.push('\n');
+    }
+    for statement in code.split(';') {
+        let statement = statement.trim();
+        if !statement.starts_with("typedef") {
+            continue
+        }
+        let declarator = match statement.find("(*") {
+            Some(i) => &statement[i + 2..],
+            None => statement.split('[').next().unwrap(),
+        };
+        let mut names = declarator.split(|c: char| !c.is_alphanumeric() && c != '_')
+            .filter(|name| !name.is_empty());
+        let name = if statement.contains("(*") { names.next() } else { names.next_back() };
+        if let Some(name) = name {
+            if is_reserved(name) {
+                return fatal(ec, span, &format!(
+                    "`{}` is defined by rust-c, and may not be redefined in a c! block", name));
+            }
+        }
+    }
+
+    // Add the #include statement to the output
+    st.headers.push(format!("{}{}\n", line_pragma(ec, span), reindent(&text)));
+
+    Ok(())
+}
+
+// The names which rust-c defines in the generated code and rust_types.h.
+// Redefining one of them would otherwise be reported as an error somewhere in
+// code the user never wrote, if at all.
+fn is_reserved(name: &str) -> bool {
+    const TYPES: &'static [&'static str] = &[
+        "i8", "i16", "i32", "i64", "isize",
+        "u8", "u16", "u32", "u64", "usize",
+        "f32", "f64", "bool_", "char_",
+    ];
+    name.starts_with("RUST_C_") || name.starts_with("rust_c_") ||
+        name.starts_with("RUST_TYPES_") || name.starts_with("rust_types_") ||
+        name.starts_with("_RUST_TYPES_H_") || TYPES.contains(&name)
+}
+
+fn expand_source<'s>(ec: &mut ExtCtxt<'s>,
+                     parser: &mut parser::Parser<'s>,
+                     st: &mut State,
+                     _: Span)
+                     -> PResult<'s, ()> {
+    let span = parser.span;
+    let (path, _) = try!(parser.parse_str());
+
+    // Paths are relative to the crate root, as the source path given to
+    // build() is
+    let path = match env::var_os("CARGO_MANIFEST_DIR") {
+        Some(dir) => Path::new(&dir).join(&*path),
+        None => PathBuf::from(&*path),
+    };
+
+    if !path.is_file() {
+        return fatal(ec, span, &format!("C source file {} does not exist", path.display()))
+    }
+
+    st.sources.push(path);
+
+    Ok(())
+}
+
+// Parse a single `name: rust_type as "c_type"` argument, returning the C
+// parameters it is passed as
+fn parse_arg<'s>(p: &mut parser::Parser<'s>,
+                 id: ast::Ident,
+                 type_sizes: &[(String, String)],
+                 checks: &mut Vec<String>,
+                 warnings: &mut Vec<String>)
+                 -> PResult<'s, String> {
+    let name = try!(p.parse_ident());
+    try!(p.expect(&Token::Colon));
+    let ty_span = p.span;
+    let ty = try!(p.parse_ty_sum());
+    try!(p.expect_keyword(keywords::As));
+
+    // A Vec owns its buffer, which C has no way to free
+    if let ast::TyKind::Path(None, ref path) = ty.node {
+        if path.segments.last().map_or(false, |s| s.identifier.name.as_str() == "Vec") {
+            return Err(p.span_fatal(ty_span, &format!(
+                "A Vec can't be passed to C by value; take `{}: &[T]` or `&Vec<T>` as a \
+                 pointer and a length instead, and pass it `&vec[..]`", name)));
+        }
+    }
+
+    if p.check(&token::OpenDelim(token::Paren)) {
+        let span = p.span;
+        let ctys: Vec<_> = try!(p.parse_unspanned_seq(
+            &token::OpenDelim(token::Paren),
+            &token::CloseDelim(token::Paren),
+            common::SeqSep::trailing_allowed(token::Comma),
+            |p| p.parse_str().map(|(cty, _)| cty.to_string())));
+
+        // A tuple is passed as one argument per element, suffixed
+        // with the element's index
+        if let ast::TyKind::Tup(ref elems) = ty.node {
+            // The c! macro only splits up tuples of these lengths
+            if elems.len() < 2 || elems.len() > 4 {
+                return Err(p.span_fatal(ty_span, &format!(
+                    "`{}` is a tuple of {} elements, but only tuples of 2 to 4 elements \
+                     can be passed to C", name, elems.len())));
+            }
+            if elems.len() != ctys.len() {
+                return Err(p.span_fatal(span, &format!(
+                    "Expected {} C types, one for each element of the tuple",
+                    elems.len())));
+            }
+
+            let params: Vec<_> = ctys.iter()
+                .enumerate()
+                .map(|(i, cty)| format!("{} {}_{}", cty, name, i))
+                .collect();
+            return Ok(params.join(", "));
+        }
+
+        // A slice is passed as a pointer and a length, the latter
+        // being named after the argument with a `_len` suffix
+        if ctys.len() != 2 {
+            return Err(p.span_fatal(span, "Expected a pair of C types, for the pointer and length"));
+        }
+        try!(check_const(p, span, name, &ty, &ctys[0]));
+
+        // The C compiler can't otherwise tell that the elements it is given
+        // are the wrong size, such as if bool isn't a single byte, or the
+        // struct of an earlier block is laid out differently
+        let size = slice_element(&ty)
+            .and_then(|elem| rust_size(elem).map(String::from).or_else(|| type_size(type_sizes, elem)));
+        if let Some(size) = size {
+            if !ctys[0].contains("void") {
+                checks.push(format!(
+                    "RUST_C_ASSERT({}_{}_elements_have_the_size_of_their_rust_type, sizeof(*({})0) == {});\n",
+                    id, name, ctys[0], size));
+            }
+        }
+        if let Some(elem) = slice_element(&ty) {
+            check_aliasing(id, name, elem, &ctys[0], warnings);
+        }
+
+        return Ok(format!("{} {}, {} {}_len", ctys[0], name, ctys[1], name));
+    }
+
+    let cty_span = p.span;
+    let (cty, _) = try!(p.parse_str());
+    try!(check_const(p, cty_span, name, &ty, &cty));
+
+    // A closure is passed as a pointer to it, and a thunk which calls it,
+    // which the c! macro only does given this C type
+    if is_closure(&ty) && &*cty != "rust_c_fn" {
+        return Err(p.span_fatal(cty_span, &format!(
+            "`{}` is a closure, whose C type must be \"rust_c_fn\"", name)));
+    }
+    if &*cty == "rust_c_fn" {
+        return Ok(format!("void *{}, rust_c_thunk {}_call", name, name))
+    }
+
+    // A Vec which C pushes onto is passed as a pointer to it, and a thunk
+    // which pushes an element onto it, given that pointer
+    if let Some(elem) = pushed_element(&ty) {
+        let size = rust_size(elem).map(String::from).or_else(|| type_size(type_sizes, elem));
+        if let Some(size) = size {
+            checks.push(format!(
+                "RUST_C_ASSERT({}_{}_elements_have_the_size_of_their_rust_type, sizeof({}) == {});\n",
+                id, name, cty, size));
+        }
+        return Ok(format!("void *{}, void (*{}_push)(void *, {})", name, name, cty))
+    }
+
+    // A reference to a raw pointer is an out-parameter, so its C type must
+    // be a pointer to a pointer to elements of the right size
+    if let Some(size) = out_pointee(&ty).and_then(rust_size) {
+        if !cty.contains("void") {
+            checks.push(format!(
+                "RUST_C_ASSERT({}_{}_points_to_pointers_to_its_rust_type, sizeof(**({})0) == {});\n",
+                id, name, cty, size));
+        }
+    }
+
+    // As with slices, C can't otherwise tell that what it is pointed to is
+    // the wrong size, including the structs and enums of earlier blocks
+    if let Some(pointee) = pointee(&ty) {
+        let size = rust_size(pointee).map(String::from).or_else(|| type_size(type_sizes, pointee));
+        if let Some(size) = size {
+            if !cty.contains("void") {
+                checks.push(format!(
+                    "RUST_C_ASSERT({}_{}_points_to_its_rust_type, sizeof(*({})0) == {});\n",
+                    id, name, cty, size));
+            }
+        }
+
+        // A reference to an array is a thin pointer to the whole array. Given
+        // as a pointer to an array, such as `const uint8_t (*)[4]`, it must
+        // be as long as rust's; otherwise it points to the first element.
+        if let ast::TyKind::Array(ref elem, ref len) = pointee.node {
+            if let Some(size) = rust_size(elem) {
+                if cty.contains("(*)") {
+                    checks.push(format!(
+                        "RUST_C_ASSERT({}_{}_points_to_its_rust_type, sizeof(*({})0) == ({}) * {});\n",
+                        id, name, cty, pprust::expr_to_string(len), size));
+                } else if !cty.contains("void") {
+                    checks.push(format!(
+                        "RUST_C_ASSERT({}_{}_elements_have_the_size_of_their_rust_type, sizeof(*({})0) == {});\n",
+                        id, name, cty, size));
+                }
+            }
+        }
+        check_aliasing(id, name, pointee, &cty, warnings);
+    }
+
+    // A struct or enum shared by an earlier block is passed by value, so an
+    // enum is passed as the integer type of its repr
+    if let Some(size) = type_size(type_sizes, &ty) {
+        checks.push(format!(
+            "RUST_C_ASSERT({}_{}_has_the_size_of_its_rust_type, sizeof({}) == {});\n",
+            id, name, cty, size));
+    }
+
+    Ok(declaration(&cty, name))
+}
+
+// Declares a C argument of the given type, putting its name inside the
+// declarator of a pointer to an array, such as `const uint8_t (*)[4]`
+fn declaration(cty: &str, name: ast::Ident) -> String {
+    if cty.contains("(*)") {
+        cty.replacen("(*)", &format!("(*{})", name), 1)
+    } else {
+        format!("{} {}", cty, name)
+    }
+}
+
+// A shared reference to plain data is given to C as a pointer to const, so
+// that the C compiler rejects any attempt to change what it points to
+fn check_const<'s>(p: &mut parser::Parser<'s>,
+                   span: Span,
+                   name: ast::Ident,
+                   ty: &ast::Ty,
+                   cty: &str)
+                   -> PResult<'s, ()> {
+    if let Some(reference) = wrapped(ty, "Option") {
+        return check_const(p, span, name, reference, cty)
+    }
+    if let ast::TyKind::Rptr(_, ast::MutTy { ref ty, mutbl: ast::Mutability::Immutable }) = ty.node {
+        let pointee = match ty.node {
+            ast::TyKind::Slice(ref elem) => &**elem,
+            _ => &**ty,
+        };
+        if is_plain(pointee) && !points_to_const(cty) {
+            return Err(p.span_fatal(span, &format!(
+                "`{}` is a shared reference, which C must not change what it points to \
+                 through; give it a pointer to const, such as `const {}`", name, cty)));
+        }
+    }
+    Ok(())
 }
 
-fn line_pragma(ec: &mut ExtCtxt, span: Span) -> String {
-    match ec.parse_sess.codemap().span_to_lines(span) {
-        Ok(FileLines{ref file, ref lines}) if !lines.is_empty() =>
-            format!("#line {} {:?}\n", lines[0].line_index + 1, file.name),
-        _ => String::new(),
+// Whether a rust type is a number, bool or char, or an array of them, which
+// can't be changed through a shared reference
+fn is_plain(ty: &ast::Ty) -> bool {
+    match ty.node {
+        ast::TyKind::Path(None, ref path) if path.segments.len() == 1 => {
+            rust_size(ty).is_some() && !path.segments[0].identifier.name.as_str().starts_with("Atomic")
+        }
+        ast::TyKind::Array(ref elem, _) => is_plain(elem),
+        _ => false,
     }
 }
 
-fn read_code_block<'s>(ec: &mut ExtCtxt<'s>,
-                       parser: &mut parser::Parser<'s>)
-                       -> PResult<'s, (Span, String)> {
-    match try!(parser.parse_token_tree()) {
-        TokenTree::Token(span, token::Literal(token::Str_(s), _)) |
-        TokenTree::Token(span, token::Literal(token::StrRaw(s, _), _)) => {
-            let s = ast::Ident::with_empty_ctxt(s).name.as_str();
-            Ok((span, s.to_string()))
-        }
-        TokenTree::Delimited(_, ref del) if del.delim == token::Brace => {
-            let span = Span {
-                lo: del.open_span.hi,
-                hi: del.close_span.lo,
-                expn_id: del.open_span.expn_id,
-            };
+// Whether what a C pointer type points to is const qualified
+fn points_to_const(cty: &str) -> bool {
+    let pointee = cty.split('*').next().unwrap_or("");
+    pointee.split(|c: char| !c.is_alphanumeric() && c != '_').any(|word| word == "const")
+}
 
-            Ok((span, try!(span_snippet(ec, span))))
+// C compilers assume that a pointer to an integer and a pointer to a float
+// never point to the same memory, so C code reading rust's values through
+// the other kind of pointer may be miscompiled. Only pointers to known number
+// types are checked; char pointers may point to anything.
+fn check_aliasing(id: ast::Ident, name: ast::Ident, ty: &ast::Ty, cty: &str,
+                  warnings: &mut Vec<String>) {
+    if let (Some(kind), Some(ckind)) = (rust_number_kind(ty), c_pointee_kind(cty)) {
+        if kind != ckind {
+            warnings.push(format!(
+                "rust-c: `{}` of the c! function `{}` points to {} values, which C is given as `{}`, \
+                 a pointer to {} values. As C assumes that these never point to the same memory, \
+                 reading through it may be miscompiled; point to {} values in C too, or copy the \
+                 bytes with memcpy",
+                name, id, kind, cty, ckind, kind));
         }
-        tt => return fatal(ec, tt.get_span(), "Unexpected token while parsing import")
     }
 }
 
-fn expand_include<'s>(ec: &mut ExtCtxt<'s>,
-                      parser: &mut parser::Parser<'s>,
-                      st: &mut State,
-                      _: Span)
-                      -> PResult<'s, ()> {
-    let (span, text) = match try!(parser.parse_token_tree()) {
-        // < foo >
-        TokenTree::Token(lo_span, Token::Lt) => {
-            let hi;
-            loop {
-                match try!(parser.parse_token_tree()) {
-                    TokenTree::Token(hi_span, Token::Gt) => {
-                        hi = hi_span.hi;
-                        break;
-                    }
-                    TokenTree::Token(span, Token::Eof) =>
-                        return fatal(ec, span, "Unexpected EOF while parsing import"),
-                    _ => continue,
-                }
+// Whether a rust type is an integer or a floating point type, other than the
+// single byte integers, which C may read through a char pointer
+fn rust_number_kind(ty: &ast::Ty) -> Option<&'static str> {
+    match ty.node {
+        ast::TyKind::Path(None, ref path) if path.segments.len() == 1 => {
+            match &*path.segments[0].identifier.name.as_str() {
+                "i16" | "u16" | "i32" | "u32" | "i64" | "u64" | "isize" | "usize" => Some("integer"),
+                "f32" | "f64" => Some("floating point"),
+                _ => None,
             }
-
-            let span = Span {
-                lo: lo_span.lo,
-                hi: hi,
-                expn_id: lo_span.expn_id,
-            };
-
-            (span, try!(span_snippet(ec, span)))
         }
-
-        // "foo"
-        TokenTree::Token(span, Token::Literal(token::Lit::Str_(_), _)) =>
-            (span, try!(span_snippet(ec, span))),
-
-        tt => return fatal(ec, tt.get_span(), "Unexpected token while parsing import")
-    };
-
-    // Add the #include statement to the output
-    st.includes.push_str(&line_pragma(ec, span));
-    st.includes.push_str(&format!("#include {}\n", text));
-
-    Ok(())
+        _ => None,
+    }
 }
 
-fn expand_raw<'s>(ec: &mut ExtCtxt<'s>,
-                  parser: &mut parser::Parser<'s>,
-                  st: &mut State,
-                  _: Span)
-                  -> PResult<'s, ()> {
-    let (span, text) = try!(read_code_block(ec, parser));
-
-    // Add the #include statement to the output
-    st.headers.push_str(&line_pragma(ec, span));
-    st.headers.push_str(&format!("{}\n", text));
+// Whether a C pointer type points to integers or floating point numbers, if
+// it is a pointer to a known number type
+fn c_pointee_kind(cty: &str) -> Option<&'static str> {
+    let cty = cty.trim_end();
+    if !cty.ends_with('*') {
+        return None
+    }
+    c_number_kind(&cty[..cty.len() - 1])
+}
 
-    Ok(())
+// Whether a C type is an integer or a floating point type, if it is a known
+// number type
+fn c_number_kind(cty: &str) -> Option<&'static str> {
+    let words: Vec<_> = cty.split_whitespace()
+        .filter(|&word| word != "const" && word != "volatile")
+        .collect();
+    match &*words.join(" ") {
+        "float" | "double" | "long double" | "f32" | "f64" => Some("floating point"),
+        "short" | "int" | "long" | "long long" | "unsigned" | "unsigned short" |
+        "unsigned int" | "unsigned long" | "unsigned long long" |
+        "int16_t" | "int32_t" | "int64_t" | "intptr_t" | "ptrdiff_t" |
+        "uint16_t" | "uint32_t" | "uint64_t" | "uintptr_t" | "size_t" |
+        "i16" | "i32" | "i64" | "isize" | "u16" | "u32" | "u64" | "usize" => Some("integer"),
+        _ => None,
+    }
 }
 
 fn expand_fn<'s>(ec: &mut ExtCtxt<'s>,
                  parser: &mut parser::Parser<'s>,
                  st: &mut State,
-                 _: Span)
+                 fn_span: Span,
+                 attrs: &Attrs)
                  -> PResult<'s, ()> {
     let mut name_args = String::new();
 
@@ -279,21 +2190,53 @@ fn expand_fn<'s>(ec: &mut ExtCtxt<'s>,
     let id = try!(parser.parse_ident());
     name_args.push_str(&id.name.as_str());
 
-    // Parse the argument list
-    let args: Vec<_> =
-        try!(parser.parse_unspanned_seq(
-            &token::OpenDelim(token::Paren),
-            &token::CloseDelim(token::Paren),
-            common::SeqSep::trailing_allowed(token::Comma),
-            |p| {
-                let name = try!(p.parse_ident());
-                try!(p.expect(&Token::Colon));
-                try!(p.parse_ty_sum());
-                try!(p.expect_keyword(keywords::As));
-                let (cty, _) = try!(p.parse_str());
+    // Lifetime parameters only matter to the rust declaration
+    try!(parser.parse_generics());
 
-                Ok(format!("{} {}", cty, name))
-            }));
+    // Parse the argument list, along with anything the C compiler should
+    // check about them
+    let mut checks = Vec::new();
+    let args = parser.parse_unspanned_seq(
+        &token::OpenDelim(token::Paren),
+        &token::CloseDelim(token::Paren),
+        common::SeqSep::trailing_allowed(token::Comma),
+        |p| {
+            let span = p.span;
+            parse_arg(p, id, &st.type_sizes, &mut checks, &mut st.warnings).map_err(|mut e| {
+                e.span_note(span, "While parsing this argument");
+                e
+            })
+        });
+    let mut args: Vec<_> = match args {
+        Ok(args) => args,
+        Err(mut e) => {
+            e.span_note(fn_span, "While parsing the arguments of this c! function");
+            return Err(e)
+        }
+    };
+
+    // The actual function declaration, and anything it needs declared first
+    let mut func = String::new();
+    let mut decls = checks;
+
+    // Parse the return type, defaulting to 'void' if no type is provided
+    let mut returns_vec = false;
+    if parser.eat(&token::RArrow) {
+        returns_vec = check_ident(parser, "Vec");
+        match parse_return(parser, id, &st.type_sizes, &mut args, &mut decls) {
+            Ok(cty) => func.push_str(&cty),
+            Err(mut e) => {
+                e.span_note(fn_span, "The return type of this c! function could not be mapped to C");
+                e.note("Return types are written as `rust_type as \"c_type\"`, \
+                        for example `-> i32 as \"int32_t\"`");
+                return Err(e)
+            }
+        }
+    } else {
+        func.push_str("void");
+    }
+
+    let parameters = args.clone();
 
     name_args.push('(');
 
@@ -307,31 +2250,204 @@ fn expand_fn<'s>(ec: &mut ExtCtxt<'s>,
     name_args.push_str(&just_args);
     name_args.push(')');
 
-    // The actual function declaration
-    let mut func = String::new();
+    // An exception can't unwind into rust, so if the code is compiled as
+    // C++, one which escapes is better off calling std::terminate
+    name_args.push_str(" RUST_C_NOEXCEPT");
 
-    // Parse the return type, defaulting to 'void' if no type is provided
-    if parser.eat(&token::RArrow) {
-        // XXX: Allow ! as a return type?
+    let (span, code) = try!(read_code_block(ec, parser));
+
+    // What the declarations need is shared by every variant, so is only
+    // declared once
+    st.prototypes.push(decls.concat());
+
+    // A Vec's array is freed by the C library which allocated it, which may
+    // not be the one rust links, such as with MSVC's debug and release
+    // runtimes. Each variant's wrapper frees it under its own name.
+    if returns_vec {
+        let names = Some(id.name.as_str().to_string()).into_iter()
+            .chain(attrs.variants.iter().map(|&(ref id, _)| id.clone()));
+        for name in names {
+            st.prototypes.push(format!("void rust_c_free_{}(void *ptr) RUST_C_NOEXCEPT;\n", name));
+            st.fndecls.push(format!("void rust_c_free_{}(void *ptr) RUST_C_NOEXCEPT {{ free(ptr); }}\n", name));
+        }
+    }
+
+    // Each variant is the same function under another name, compiled on its
+    // own for the CPU feature it is named for
+    let id_str = id.name.as_str().to_string();
+    let names = Some((id_str.clone(), None)).into_iter()
+        .chain(attrs.variants.iter().map(|&(ref id, ref feature)| (id.clone(), Some(feature.clone()))));
+    for (name, feature) in names {
+        st.functions.push(FunctionInfo {
+            name: name.clone(),
+            return_type: func.clone(),
+            parameters: parameters.clone(),
+            location: location(ec, fn_span),
+        });
+
+        let mut func = format!("{} {}{}", func, name, &name_args[id_str.len()..]);
+
+        // Every function is declared ahead of the definitions, so that they
+        // may call one another in any order, and from any file
+        st.prototypes.push(format!("{}{};\n", line_pragma(ec, fn_span), func));
+
+        func.push_str(" {");
+
+        // Every parameter is used, so that one which the body doesn't use
+        // isn't warned about. The body is in a block of its own, as C89
+        // doesn't allow its declarations to follow statements.
+        let names = param_names(&parameters);
+        for name in &names {
+            func.push_str(&format!(" (void){};", name));
+        }
+        if !names.is_empty() {
+            func.push_str(" {");
+        }
+
+        // Read the body. A stub's body is left out, and replaced with one
+        // which aborts; the stub is weak, so that a real definition may
+        // replace it. MSVC has no weak definitions, so there it can't be.
+        if attrs.stub {
+            func.insert_str(0, "RUST_C_WEAK ");
+            func.push_str(" abort(); ");
+        } else {
+            func.push_str(&reindent(&code));
+        }
+
+        // Only the definition is marked inline, so that it is still an
+        // external definition, which rust can call
+        if attrs.always_inline {
+            func.insert_str(0, "RUST_C_ALWAYS_INLINE ");
+        }
+        if !names.is_empty() {
+            func.push_str("}");
+        }
+        func.push_str("}");
+
+        // Write out the function declaration
+        let fndecl = format!("{}{}\n", line_pragma(ec, span), func);
+        if attrs.out_of_line || feature.is_some() {
+            st.out_of_line.push((name, fndecl, feature));
+        } else {
+            st.fndecls.push(fndecl);
+        }
+    }
+
+    Ok(())
+}
+
+// The names of the C parameters, given the parameters of each argument, some
+// of which are declarators such as `void (*v_push)(void *, uint32_t)`
+fn param_names(args: &[String]) -> Vec<String> {
+    let mut names = Vec::new();
+    for arg in args {
+        let mut depth = 0;
+        let mut start = 0;
+        for (i, c) in arg.char_indices().chain(Some((arg.len(), ','))) {
+            match c {
+                '(' | '[' => depth += 1,
+                ')' | ']' => depth -= 1,
+                ',' if depth == 0 => {
+                    names.push(param_name(&arg[start..i]));
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+    }
+    names
+}
+
+fn param_name(param: &str) -> String {
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+    match param.find("(*") {
+        Some(i) => param[i + 2..].trim_start().chars().take_while(|&c| is_ident(c)).collect(),
+        None => {
+            let param = param.trim_end();
+            let start = param.rfind(|c: char| !is_ident(c)).map_or(0, |i| i + 1);
+            param[start..].to_string()
+        }
+    }
+}
+
+fn expand_const<'s>(ec: &mut ExtCtxt<'s>,
+                    parser: &mut parser::Parser<'s>,
+                    st: &mut State,
+                    kw_span: Span)
+                    -> PResult<'s, ()> {
+    let id_span = parser.span;
+    let id = try!(parser.parse_ident());
+    if is_reserved(&id.name.as_str()) {
+        return fatal(ec, id_span, &format!(
+            "`{}` is defined by rust-c, and may not be redefined in a c! block", id));
+    }
+    try!(parser.expect(&Token::Colon));
+    let cty = try!(parse_c_type(parser));
+    try!(parser.expect(&Token::Eq));
+
+    // The value must be a literal, which is written out again in a form that
+    // C will accept
+    let sign = if parser.eat(&Token::BinOp(token::Minus)) { "-" } else { "" };
+    let span = parser.span;
+    let lit = try!(parser.parse_lit());
+    let value = match lit.node {
+        ast::LitKind::Int(n, _) if n > i64::max_value() as u64 => format!("{}ULL", n),
+        ast::LitKind::Int(n, _) => n.to_string(),
+        ast::LitKind::Float(ref f, _) |
+        ast::LitKind::FloatUnsuffixed(ref f) => f.replace("_", ""),
+        ast::LitKind::Bool(b) => (b as u8).to_string(),
+        _ => return fatal(ec, span, "Only integer, float and bool constants may be shared with C"),
+    };
+    try!(parser.expect(&Token::Semi));
+
+    // A #define, rather than a const variable, so that it may be used where C
+    // requires a constant expression, such as the size of an array
+    st.headers.push(format!("{}#define {} (({}){}{})\n",
+                            line_pragma(ec, kw_span), id, cty, sign, value));
+
+    Ok(())
+}
+
+// An opaque type is declared to C, but never defined, so that pointers to it
+// may be passed around without C being able to look inside
+fn expand_opaque<'s>(ec: &mut ExtCtxt<'s>,
+                     parser: &mut parser::Parser<'s>,
+                     st: &mut State,
+                     kw_span: Span)
+                     -> PResult<'s, ()> {
+    let id_span = parser.span;
+    let id = try!(parser.parse_ident());
+    if is_reserved(&id.name.as_str()) {
+        return fatal(ec, id_span, &format!(
+            "`{}` is defined by rust-c, and may not be redefined in a c! block", id));
+    }
+
+    // The rust type it stands for is no concern of C's
+    if parser.eat(&Token::Eq) {
         try!(parser.parse_ty());
-        try!(parser.expect_keyword(keywords::As));
-        let (cty, _) = try!(parser.parse_str());
-        func.push_str(&cty);
-    } else {
-        func.push_str("void");
     }
-    func.push(' ');
-    func.push_str(&name_args);
-    func.push_str(" {");
+    try!(parser.expect(&Token::Semi));
 
-    // Read the body
-    let (span, code) = try!(read_code_block(ec, parser));
-    func.push_str(&code);
-    func.push_str("}");
+    st.forward_decls.push(format!("typedef struct {} {};\n", id, id));
+    st.types.push(TypeInfo {
+        name: id.name.as_str().to_string(),
+        kind: "opaque",
+        location: location(ec, kw_span),
+    });
+
+    Ok(())
+}
 
-    // Write out the function declaration
-    st.fndecls.push_str(&line_pragma(ec, span));
-    st.fndecls.push_str(&format!("{}\n", func));
+// A handle only wraps a `void *` in rust, so C needn't know of it
+fn expand_handle<'s>(_: &mut ExtCtxt<'s>,
+                     parser: &mut parser::Parser<'s>,
+                     _: &mut State,
+                     _: Span)
+                     -> PResult<'s, ()> {
+    try!(parser.parse_ident());
+    try!(parser.expect(&Token::Eq));
+    try!(parser.parse_ident());
+    try!(parser.expect(&Token::Semi));
 
     Ok(())
 }
@@ -339,8 +2455,10 @@ fn expand_fn<'s>(ec: &mut ExtCtxt<'s>,
 fn expand_enum<'s>(ec: &mut ExtCtxt<'s>,
                    parser: &mut parser::Parser<'s>,
                    st: &mut State,
-                   kw_span: Span)
+                   kw_span: Span,
+                   attrs: &Attrs)
                    -> PResult<'s, ()> {
+    let repr = attrs.repr;
     let mut s = format!("enum ");
     let mut add_prefix = false;
 
@@ -348,11 +2466,25 @@ fn expand_enum<'s>(ec: &mut ExtCtxt<'s>,
     // relevant information and parse another ident
     let mut id = try!(parser.parse_ident());
 
-    s.push_str(&id.name.as_str());
-    s.push_str(" {\n");
+    // An enum with an integer repr is passed as that integer type, so its
+    // values are an anonymous enum, as in C++ the enum's name would clash
+    // with the typedef
+    if repr.is_none() {
+        s.push_str(&id.name.as_str());
+        s.push(' ');
+    }
+    s.push_str("{\n");
+
+    // The variants of a flags enum are bits, which rust can only hold in
+    // the integer type it wraps
+    if attrs.flags && repr.is_none() {
+        return fatal(ec, kw_span, &format!(
+            "The #[flags] enum `{}` must be given an integer repr, such as #[repr(u32)]", id));
+    }
 
     // Parse the argument list
     let mut opts = String::new();
+    let mut bit = 0;
     try!(parser.parse_unspanned_seq(
         &token::OpenDelim(token::Brace),
         &token::CloseDelim(token::Brace),
@@ -360,11 +2492,15 @@ fn expand_enum<'s>(ec: &mut ExtCtxt<'s>,
         |p| {
             let name = try!(p.parse_ident());
 
-            let name_str = if add_prefix {
+            let mut name_str = if add_prefix {
                 format!("{}_{}", id, name)
             } else {
                 format!("{}", name)
             };
+            if attrs.flags {
+                name_str.push_str(&format!(" = 1 << {}", bit));
+                bit += 1;
+            }
 
             if opts.is_empty() {
                 opts.push_str("    ");
@@ -374,19 +2510,169 @@ fn expand_enum<'s>(ec: &mut ExtCtxt<'s>,
             opts.push_str(&name_str);
             Ok(())
         }));
+
+    // Each variant is a bit of the repr, less a signed repr's sign bit, and
+    // C's enum values may be no bigger than an int
+    let bits = match repr {
+        Some("int8_t") => 7,
+        Some("uint8_t") => 8,
+        Some("int16_t") => 15,
+        Some("uint16_t") => 16,
+        _ => 31,
+    };
+    if bit > bits {
+        return fatal(ec, kw_span, &format!(
+            "The #[flags] enum `{}` has more than {} variants, so they can't all be bits of its repr and a C int",
+            id, bits));
+    }
     s.push_str(&opts);
     s.push_str("\n};\n");
 
-    st.headers.push_str(&line_pragma(ec, kw_span));
-    st.headers.push_str(&s);
+    // A C enum is always as wide as an int, so an enum with an integer repr
+    // is passed as that integer type instead
+    match repr {
+        Some(cty) => s.push_str(&format!("typedef {} {};\n", cty, id)),
+        None => s.push_str(&format!("typedef enum {} {};\n", id, id)),
+    }
+    s.push_str(&size_and_align(&id.name.as_str()));
+
+    // Enums are defined ahead of the headers, as they can't be declared ahead
+    // of the structs which contain them
+    st.enums.push(format!("{}{}{}", doc_comment(&attrs.doc), line_pragma(ec, kw_span), s));
+    st.types.push(TypeInfo {
+        name: id.name.as_str().to_string(),
+        kind: "enum",
+        location: location(ec, kw_span),
+    });
+    st.type_sizes.push((id.name.as_str().to_string(),
+                        format!("sizeof({})", repr.unwrap_or("int"))));
 
     Ok(())
 }
 
+// The attributes given to an item which are of interest to the C code
+#[derive(Debug, Default)]
+struct Attrs {
+    // The C type of the integer type given by #[repr(...)]
+    repr: Option<&'static str>,
+    // Whether the item is marked #[stub]
+    stub: bool,
+    // Whether the item is marked #[out_of_line]
+    out_of_line: bool,
+    // Whether the item is marked #[always_inline]
+    always_inline: bool,
+    // The name and CPU feature of each #[variant(name = "feature")]
+    variants: Vec<(String, String)>,
+    // Whether the item is marked #[flags]
+    flags: bool,
+    // Whether the item is marked #[after_types]
+    after_types: bool,
+    // Whether the item is marked #[derive(PartialEq)]
+    partial_eq: bool,
+    // The lines of the item's doc comments
+    doc: Vec<String>,
+}
+
+impl Attrs {
+    // Record an attribute, given the tokens between its brackets
+    fn add(&mut self, tts: &[TokenTree]) {
+        if let Some(cty) = repr_type(tts) {
+            self.repr = Some(cty);
+        }
+
+        if let (3, Some(&TokenTree::Token(_, Token::Ident(ref attr))),
+                Some(&TokenTree::Token(_, Token::Literal(token::Lit::Str_(doc), _)))) =
+            (tts.len(), tts.get(0), tts.get(2)) {
+            if attr.name.as_str() == "doc" {
+                self.doc.push(doc.as_str().to_string());
+            }
+        }
+
+        if let (2, Some(&TokenTree::Token(_, Token::Ident(ref attr))), Some(&TokenTree::Delimited(_, ref args))) =
+            (tts.len(), tts.get(0), tts.get(1)) {
+            if let (true, 3, Some(&TokenTree::Token(_, Token::Ident(ref id))),
+                    Some(&TokenTree::Token(_, Token::Literal(token::Lit::Str_(feature), _)))) =
+                (attr.name.as_str() == "variant", args.tts.len(), args.tts.get(0), args.tts.get(2)) {
+                self.variants.push((id.name.as_str().to_string(), feature.as_str().to_string()));
+            }
+
+            if attr.name.as_str() == "derive" {
+                self.partial_eq |= args.tts.iter().any(|tt| match *tt {
+                    TokenTree::Token(_, Token::Ident(ref id)) => id.name.as_str() == "PartialEq",
+                    _ => false,
+                });
+            }
+        }
+
+        if let (1, Some(&TokenTree::Token(_, Token::Ident(ref attr)))) = (tts.len(), tts.get(0)) {
+            if attr.name.as_str() == "stub" {
+                self.stub = true;
+            } else if attr.name.as_str() == "out_of_line" {
+                self.out_of_line = true;
+            } else if attr.name.as_str() == "always_inline" {
+                self.always_inline = true;
+            } else if attr.name.as_str() == "flags" {
+                self.flags = true;
+            } else if attr.name.as_str() == "after_types" {
+                self.after_types = true;
+            }
+        }
+    }
+}
+
+// A C comment holding an item's doc comments, to go above its C definition
+fn doc_comment(doc: &[String]) -> String {
+    if doc.is_empty() {
+        return String::new()
+    }
+
+    let mut s = String::from("/**\n");
+    for line in doc.iter().flat_map(|doc| doc.lines()) {
+        let line = line.trim_end().replace("*/", "* /");
+        s.push_str(" *");
+        if !line.is_empty() {
+            s.push(' ');
+            s.push_str(line.strip_prefix(' ').unwrap_or(&line));
+        }
+        s.push('\n');
+    }
+    s.push_str(" */\n");
+    s
+}
+
+// The C type corresponding to the integer type in a #[repr(...)] attribute,
+// given the tokens between its brackets
+fn repr_type(tts: &[TokenTree]) -> Option<&'static str> {
+    match (tts.get(0), tts.get(1)) {
+        (Some(&TokenTree::Token(_, Token::Ident(ref attr))),
+         Some(&TokenTree::Delimited(_, ref args)))
+            if attr.name.as_str() == "repr" && args.tts.len() == 1 => {
+            match args.tts[0] {
+                TokenTree::Token(_, Token::Ident(ref ty)) => match &*ty.name.as_str() {
+                    "i8" => Some("int8_t"),
+                    "i16" => Some("int16_t"),
+                    "i32" => Some("int32_t"),
+                    "i64" => Some("int64_t"),
+                    "isize" => Some("intptr_t"),
+                    "u8" => Some("uint8_t"),
+                    "u16" => Some("uint16_t"),
+                    "u32" => Some("uint32_t"),
+                    "u64" => Some("uint64_t"),
+                    "usize" => Some("uintptr_t"),
+                    _ => None,
+                },
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
 fn expand_struct<'s>(ec: &mut ExtCtxt<'s>,
                      parser: &mut parser::Parser<'s>,
                      st: &mut State,
-                     kw_span: Span)
+                     kw_span: Span,
+                     attrs: &Attrs)
                      -> PResult<'s, ()> {
     let mut s = format!("struct ");
 
@@ -404,25 +2690,229 @@ fn expand_struct<'s>(ec: &mut ExtCtxt<'s>,
             |p| {
                 let name = try!(p.parse_ident());
                 try!(p.expect(&Token::Colon));
-                try!(p.parse_ty_sum());
+                let ty = try!(p.parse_ty_sum());
                 try!(p.expect_keyword(keywords::As));
                 let (cty, _) = try!(p.parse_str());
 
-                Ok(format!("    {} {};\n", cty, name))
+                Ok((name, ty, cty))
             }));
 
-    for arg in args {
-        s.push_str(&arg);
+    for &(ref name, _, ref cty) in &args {
+        s.push_str(&format!("    {} {};\n", cty, name));
     }
 
     s.push_str("};\n");
 
-    st.headers.push_str(&line_pragma(ec, kw_span));
-    st.headers.push_str(&s);
+    // Both definitions come from the same list of fields, so they can only
+    // disagree if a C type is the wrong size for its rust type, or if the C
+    // compiler was told to pack the struct, such as by a #pragma pack in a
+    // header. Rust lays each field out after the one before it, at the next
+    // multiple of its alignment.
+    let mut previous = None;
+    for &(ref name, ref ty, ref cty) in &args {
+        let size = rust_size(ty).map(String::from).or_else(|| type_size(&st.type_sizes, ty));
+        if let Some(size) = size.as_ref() {
+            s.push_str(&format!(
+                "RUST_C_ASSERT({}_{}_has_the_size_of_its_rust_type, sizeof(((struct {} *)0)->{}) == {});\n",
+                id, name, id, name, size));
+        }
+        if let Some((previous, previous_size)) = previous {
+            s.push_str(&format!(
+                "RUST_C_ASSERT({}_{}_is_at_its_rust_offset, offsetof(struct {}, {}) == \
+                 RUST_C_ALIGN_UP(offsetof(struct {}, {}) + {}, RUST_C_ALIGNOF({})));\n",
+                id, name, id, name, id, previous, previous_size, cty));
+        }
+        previous = size.map(|size| (name, size));
+    }
+    s.push_str(&size_and_align(&format!("struct {}", id)));
+    st.type_sizes.push((id.name.as_str().to_string(), format!("sizeof(struct {})", id)));
+
+    if attrs.partial_eq {
+        let fields: Vec<_> = args.iter().map(|&(name, ref ty, _)| (name, &**ty)).collect();
+        s.push_str(&equality(&id.name.as_str(), &fields, &st.eq_structs));
+        st.eq_structs.push(id.name.as_str().to_string());
+    }
+
+    st.forward_decls.push(format!("typedef struct {} {};\n", id, id));
+    st.headers.push(format!("{}{}{}", doc_comment(&attrs.doc), line_pragma(ec, kw_span), s));
+    st.types.push(TypeInfo {
+        name: id.name.as_str().to_string(),
+        kind: "struct",
+        location: location(ec, kw_span),
+    });
 
     Ok(())
 }
 
+// A member-wise comparison of two of a struct which derives PartialEq, so
+// that C agrees with rust about which are equal, along with operator== and
+// operator!= for C++. Fields which are themselves such structs are compared
+// with their own rust_c_eq_ function.
+fn equality(id: &str, fields: &[(ast::Ident, &ast::Ty)], eq_structs: &[String]) -> String {
+    let mut s = format!("RUST_C_INLINE int rust_c_eq_{}(const struct {} *a, const struct {} *b)\n{{\n    return 1",
+                        id, id, id);
+    for &(ref name, ty) in fields {
+        let nested = match ty.node {
+            ast::TyKind::Path(None, ref path) if path.segments.len() == 1 => {
+                let ty_name = path.segments[0].identifier.name.as_str();
+                eq_structs.iter().find(|eq| **eq == *ty_name).cloned()
+            }
+            _ => None,
+        };
+        match nested {
+            Some(ty_name) => s.push_str(&format!("\n        && rust_c_eq_{}(&a->{}, &b->{})", ty_name, name, name)),
+            None => s.push_str(&format!("\n        && a->{} == b->{}", name, name)),
+        }
+    }
+    s.push_str(";\n}\n");
+    s.push_str(&format!(
+        "#ifdef __cplusplus\n\
+         inline bool operator==(const {} &a, const {} &b) {{ return rust_c_eq_{}(&a, &b) != 0; }}\n\
+         inline bool operator!=(const {} &a, const {} &b) {{ return rust_c_eq_{}(&a, &b) == 0; }}\n\
+         #endif\n",
+        id, id, id, id, id, id));
+    s
+}
+
+// Constants holding the size and alignment of a type shared with rust, for
+// sizing buffers at compile time. As its layout is checked against rust's,
+// they agree with rust's size_of and align_of.
+fn size_and_align(cty: &str) -> String {
+    let id = cty.trim_start_matches("struct ");
+    format!("#define sizeof_{} sizeof({})\n#define alignof_{} RUST_C_ALIGNOF({})\n",
+            id, cty, id, cty)
+}
+
+// The size of an enum or struct defined by an earlier block, as a C
+// expression
+fn type_size(type_sizes: &[(String, String)], ty: &ast::Ty) -> Option<String> {
+    match ty.node {
+        ast::TyKind::Path(None, ref path) if path.segments.len() == 1 => {
+            let name = path.segments[0].identifier.name.as_str();
+            type_sizes.iter()
+                .find(|&&(ref id, _)| *id == *name)
+                .map(|&(_, ref size)| size.clone())
+        }
+        _ => None,
+    }
+}
+
+// The type pointed to by a pointer or a reference, other than to a slice
+fn pointee(ty: &ast::Ty) -> Option<&ast::Ty> {
+    match ty.node {
+        ast::TyKind::Ptr(ref mt) |
+        ast::TyKind::Rptr(_, ref mt) => match mt.ty.node {
+            ast::TyKind::Slice(..) => None,
+            _ => Some(&*mt.ty),
+        },
+
+        // A Pin is passed as the pointer it wraps, and an Option of a
+        // reference as a pointer which may be null
+        ast::TyKind::Path(None, _) => {
+            wrapped(ty, "Pin").or_else(|| wrapped(ty, "Option")).and_then(pointee)
+        }
+        _ => None,
+    }
+}
+
+// The type wrapped by a generic type with the given name, such as the `T` of
+// an `Option<T>`
+fn wrapped<'a>(ty: &'a ast::Ty, wrapper: &str) -> Option<&'a ast::Ty> {
+    let segment = match ty.node {
+        ast::TyKind::Path(None, ref path) => path.segments.last().unwrap(),
+        _ => return None,
+    };
+    if &*segment.identifier.name.as_str() != wrapper {
+        return None
+    }
+    match segment.parameters.as_ref().map(|p| &**p) {
+        Some(&ast::PathParameters::AngleBracketed(ref data)) if data.types.len() == 1 => {
+            Some(&data.types[0])
+        }
+        _ => None,
+    }
+}
+
+// The type of the elements of a slice, or a pointer to one
+fn slice_element(ty: &ast::Ty) -> Option<&ast::Ty> {
+    match ty.node {
+        ast::TyKind::Ptr(ref mt) |
+        ast::TyKind::Rptr(_, ref mt) => match mt.ty.node {
+            ast::TyKind::Slice(ref elem) => Some(&**elem),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Whether a type is a reference to a closure
+fn is_closure(ty: &ast::Ty) -> bool {
+    let path = match pointee(ty).map(|ty| &ty.node) {
+        Some(&ast::TyKind::Path(None, ref path)) => path,
+        Some(&ast::TyKind::TraitObject(ref bounds)) => match bounds.first() {
+            Some(&ast::TraitTyParamBound(ref poly, _)) => &poly.trait_ref.path,
+            _ => return false,
+        },
+        _ => return false,
+    };
+    match &*path.segments.last().unwrap().identifier.name.as_str() {
+        "Fn" | "FnMut" => true,
+        _ => false,
+    }
+}
+
+// The type of the elements of a Vec which a mutable reference points to
+fn pushed_element(ty: &ast::Ty) -> Option<&ast::Ty> {
+    match ty.node {
+        ast::TyKind::Rptr(_, ast::MutTy { ref ty, mutbl: ast::Mutability::Mutable }) => wrapped(ty, "Vec"),
+        _ => None,
+    }
+}
+
+// The type pointed to by the raw pointer which a reference points to
+fn out_pointee(ty: &ast::Ty) -> Option<&ast::Ty> {
+    match ty.node {
+        ast::TyKind::Rptr(_, ref mt) => match mt.ty.node {
+            ast::TyKind::Ptr(ref mt) => Some(&*mt.ty),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// The size of a rust type, as a C expression, if it is known without asking
+// the rust compiler
+fn rust_size(ty: &ast::Ty) -> Option<&'static str> {
+    match ty.node {
+        // The atomics are as wide as the types they wrap, wherever they are
+        // named from
+        ast::TyKind::Path(None, ref path) => {
+            match &*path.segments.last().unwrap().identifier.name.as_str() {
+                "AtomicBool" | "AtomicI8" | "AtomicU8" => Some("1"),
+                "AtomicI16" | "AtomicU16" => Some("2"),
+                "AtomicI32" | "AtomicU32" => Some("4"),
+                "AtomicI64" | "AtomicU64" => Some("8"),
+                "AtomicIsize" | "AtomicUsize" | "AtomicPtr" => Some("sizeof(void *)"),
+                _ if path.segments.len() != 1 => None,
+                "i8" | "u8" | "bool" => Some("1"),
+                "i16" | "u16" => Some("2"),
+                "i32" | "u32" | "f32" | "char" => Some("4"),
+                "i64" | "u64" | "f64" => Some("8"),
+                "isize" | "usize" => Some("sizeof(void *)"),
+                _ => None,
+            }
+        }
+
+        // A pointer is thin if what it points to is sized, which is certainly
+        // the case if we know its size. Otherwise, it might be a slice, str
+        // or trait object.
+        ast::TyKind::Ptr(ref mt) |
+        ast::TyKind::Rptr(_, ref mt) => rust_size(&mt.ty).map(|_| "sizeof(void *)"),
+
+        _ => None,
+    }
+}
+
 struct C(Rc<RefCell<State>>);
 impl TTMacroExpander for C {
     fn expand<'cx>(&self,
@@ -434,6 +2924,7 @@ impl TTMacroExpander for C {
         let mut st = self.0.borrow_mut();
         let mut parser = ec.new_parser_from_tts(tts);
 
+        let mut attrs = Attrs::default();
         loop {
             if parser.check(&token::Eof) {
                 break
@@ -445,29 +2936,49 @@ impl TTMacroExpander for C {
                     match parser.parse_token_tree() {
                         Ok(TokenTree::Token(span, Token::Ident(ref i))) =>
                             if i.name.as_str() == "include" {
-                                expand_include(ec, &mut parser, &mut *st, span)
+                                let attrs = mem::replace(&mut attrs, Attrs::default());
+                                expand_include(ec, &mut parser, &mut *st, span, &attrs)
                             } else {
                                 fatal(ec, span, "Unrecognized token after #")
                             },
 
                         // The meta item will take the form #[...], so we can just
-                        // parse the [] as a single token tree
-                        Ok(TokenTree::Delimited(..)) => Ok(()),
+                        // parse the [] as a single token tree. Any attributes
+                        // the C code needs are remembered for the next item.
+                        Ok(TokenTree::Delimited(_, ref del)) => {
+                            attrs.add(&del.tts);
+                            Ok(())
+                        }
                         Ok(tt) => fatal(ec, tt.get_span(), "Unrecognized token after #"),
                         Err(e) => Err(e),
                     }
                 }
 
+                // Doc comments are kept for the item they document
+                Ok(TokenTree::Token(_, Token::DocComment(doc))) => {
+                    attrs.doc.push(comments::strip_doc_comment_decoration(&doc.as_str()));
+                    Ok(())
+                }
+
                 // Looking at an identifier, check which one
                 Ok(TokenTree::Token(span, Token::Ident(ref i))) => {
+                    let attrs = mem::replace(&mut attrs, Attrs::default());
                     if i.name.as_str() == "raw" {
                         expand_raw(ec, &mut parser, &mut *st, span)
+                    } else if i.name.as_str() == "source" {
+                        expand_source(ec, &mut parser, &mut *st, span)
                     } else if i.name.as_str() == "fn" {
-                        expand_fn(ec, &mut parser, &mut *st, span)
+                        expand_fn(ec, &mut parser, &mut *st, span, &attrs)
+                    } else if i.name.as_str() == "const" {
+                        expand_const(ec, &mut parser, &mut *st, span)
                     } else if i.name.as_str() == "enum" {
-                        expand_enum(ec, &mut parser, &mut *st, span)
+                        expand_enum(ec, &mut parser, &mut *st, span, &attrs)
                     } else if i.name.as_str() == "struct" {
-                        expand_struct(ec, &mut parser, &mut *st, span)
+                        expand_struct(ec, &mut parser, &mut *st, span, &attrs)
+                    } else if i.name.as_str() == "opaque" {
+                        expand_opaque(ec, &mut parser, &mut *st, span)
+                    } else if i.name.as_str() == "handle" {
+                        expand_handle(ec, &mut parser, &mut *st, span)
                     } else {
                         fatal(ec, span, "Unrecognized token")
                     }
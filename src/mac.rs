@@ -5,12 +5,16 @@ macro_rules! c {
     () => {};
 
     // Parse toplevel #include macros
+    (#[after_types] #include $($rest:tt)*) => {c!{#include $($rest)*}};
     (#include < $i:ident .h> $($rest:tt)*) => {c!{$($rest)*}};
     (#include $l:tt $($rest:tt)*) => {c!{$($rest)*}};
 
     // Parse toplevel raw macros
     (raw $body:tt $($rest:tt)*) => {c!{$($rest)*}};
 
+    // Parse toplevel source files, which are only of interest to the build
+    (source $path:tt $($rest:tt)*) => {c!{$($rest)*}};
+
     // Parse parameters
     (C_PARAM $name:ident : $t:ty as $ct:tt) => {
         $name: $t
@@ -21,21 +25,599 @@ macro_rules! c {
     };
 
     // Parse function declarations
-    ($(#[$m:meta])*
-     fn $id:ident ( $($name:ident : $t:ty as $ct:tt),* ) -> $rt:ty as $rct:tt $body:tt $($rest:tt)*) => {
+    //
+    // The arguments are munched one at a time by the C_FN rules below, which
+    // build up the parameters of the extern declaration, those of the rust
+    // function wrapping it (if one is needed), and the arguments the wrapper
+    // passes down. Once the arguments run out, the return type decides what
+    // is generated.
+    //
+    // First, though, the attributes are munched by the C_FN_ATTRS rules, to
+    // remove those which are only of interest to the C code
+    ($(#[$($a:tt)*])*
+     fn $($rest:tt)*) => {
+        c!{C_FN_ATTRS [] ($(#[$($a)*])*) fn $($rest)*}
+    };
+    (C_FN_ATTRS [$($m:tt)*] (#[stub] $($a:tt)*) $($rest:tt)*) => {
+        c!{C_FN_ATTRS [$($m)*] ($($a)*) $($rest)*}
+    };
+    (C_FN_ATTRS [$($m:tt)*] (#[out_of_line] $($a:tt)*) $($rest:tt)*) => {
+        c!{C_FN_ATTRS [$($m)*] ($($a)*) $($rest)*}
+    };
+    (C_FN_ATTRS [$($m:tt)*] (#[always_inline] $($a:tt)*) $($rest:tt)*) => {
+        c!{C_FN_ATTRS [$($m)*] ($($a)*) $($rest)*}
+    };
+    (C_FN_ATTRS [$($m:tt)*] (#[variant ( $vid:ident = $feature:tt )] $($a:tt)*)
+     fn $id:ident $($rest:tt)*) => {
+        c!{C_FN_VARIANT [$($m)*] ($($a)*) $id $vid [] $($rest)*}
+    };
+    (C_FN_ATTRS [$($m:tt)*] (#[$($attr:tt)*] $($a:tt)*) $($rest:tt)*) => {
+        c!{C_FN_ATTRS [$($m)* #[$($attr)*]] ($($a)*) $($rest)*}
+    };
+
+    // A function with a variant is declared twice, once under each name, so
+    // its signature is munched up to its body, and the two are generated
+    // apart from the items which follow. The variant's own attributes don't
+    // include the other variants.
+    (C_FN_VARIANT $m:tt $a:tt $id:ident $vid:ident [$($sig:tt)*] as $ct:tt $($rest:tt)*) => {
+        c!{C_FN_VARIANT $m $a $id $vid [$($sig)* as $ct] $($rest)*}
+    };
+    (C_FN_VARIANT [$($m:tt)*] ($($a:tt)*) $id:ident $vid:ident [$($sig:tt)*] { $($body:tt)* } $($rest:tt)*) => {
+        c!{C_FN_ATTRS [$($m)*] ($($a)*) fn $id $($sig)* { $($body)* }}
+        c!{C_FN_VARIANT_ATTRS [$($m)*] ($($a)*) fn $vid $($sig)* { $($body)* }}
+        c!{$($rest)*}
+    };
+    (C_FN_VARIANT [$($m:tt)*] ($($a:tt)*) $id:ident $vid:ident [$($sig:tt)*] $body:literal $($rest:tt)*) => {
+        c!{C_FN_ATTRS [$($m)*] ($($a)*) fn $id $($sig)* $body}
+        c!{C_FN_VARIANT_ATTRS [$($m)*] ($($a)*) fn $vid $($sig)* $body}
+        c!{$($rest)*}
+    };
+    (C_FN_VARIANT $m:tt $a:tt $id:ident $vid:ident [$($sig:tt)*] $t:tt $($rest:tt)*) => {
+        c!{C_FN_VARIANT $m $a $id $vid [$($sig)* $t] $($rest)*}
+    };
+    (C_FN_VARIANT_ATTRS [$($m:tt)*] (#[variant $v:tt] $($a:tt)*) $($rest:tt)*) => {
+        c!{C_FN_VARIANT_ATTRS [$($m)*] ($($a)*) $($rest)*}
+    };
+    (C_FN_VARIANT_ATTRS [$($m:tt)*] (#[$($attr:tt)*] $($a:tt)*) $($rest:tt)*) => {
+        c!{C_FN_VARIANT_ATTRS [$($m)* #[$($attr)*]] ($($a)*) $($rest)*}
+    };
+    (C_FN_VARIANT_ATTRS [$($m:tt)*] () $($rest:tt)*) => {
+        c!{C_FN_ATTRS [] ($($m)*) $($rest)*}
+    };
+    (C_FN_ATTRS $m:tt ()
+     fn $id:ident $(< $($lt:lifetime),* >)? ( $($args:tt)* ) $($rest:tt)*) => {
+        c!{C_FN $m $id [$($($lt),*)?] [] [] [] [] ( $($args)* , ) $($rest)*}
+    };
+
+    // A slice is passed to C as a pointer and a length
+    (C_FN $m:tt $id:ident $lt:tt [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] $mode:tt
+     ( $name:ident : & $($l:lifetime)* [ $et:ty ] as ( $pct:tt , $lct:tt ) , $($args:tt)* ) $($rest:tt)*) => {
+        c!{C_FN $m $id $lt
+           [$($ext)* _ : *const $et , _ : usize ,]
+           [$($wrap)* $name : & $($l)* [ $et ] ,]
+           [$($call)* $name.as_ptr() , $name.len() ,]
+           [wrap] ( $($args)* ) $($rest)*}
+    };
+    (C_FN $m:tt $id:ident $lt:tt [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] $mode:tt
+     ( $name:ident : & $($l:lifetime)* mut [ $et:ty ] as ( $pct:tt , $lct:tt ) , $($args:tt)* ) $($rest:tt)*) => {
+        c!{C_FN $m $id $lt
+           [$($ext)* _ : *mut $et , _ : usize ,]
+           [$($wrap)* $name : & $($l)* mut [ $et ] ,]
+           [$($call)* $name.as_mut_ptr() , $name.len() ,]
+           [wrap] ( $($args)* ) $($rest)*}
+    };
+
+    // So is a str, unless it is given a single C type, in which case it is
+    // copied into a nul-terminated string which lives until the call returns
+    (C_FN $m:tt $id:ident $lt:tt [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] $mode:tt
+     ( $name:ident : & $($l:lifetime)* str as ( $pct:tt , $lct:tt ) , $($args:tt)* ) $($rest:tt)*) => {
+        c!{C_FN $m $id $lt
+           [$($ext)* _ : *const u8 , _ : usize ,]
+           [$($wrap)* $name : & $($l)* str ,]
+           [$($call)* $name.as_ptr() , $name.len() ,]
+           [wrap] ( $($args)* ) $($rest)*}
+    };
+    (C_FN $m:tt $id:ident $lt:tt [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] $mode:tt
+     ( $name:ident : & $($l:lifetime)* mut str as ( $pct:tt , $lct:tt ) , $($args:tt)* ) $($rest:tt)*) => {
+        c!{C_FN $m $id $lt
+           [$($ext)* _ : *mut u8 , _ : usize ,]
+           [$($wrap)* $name : & $($l)* mut str ,]
+           [$($call)* $name.as_mut_ptr() , $name.len() ,]
+           [wrap] ( $($args)* ) $($rest)*}
+    };
+    (C_FN $m:tt $id:ident $lt:tt [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] $mode:tt
+     ( $name:ident : & $($l:lifetime)* str as $ct:tt , $($args:tt)* ) $($rest:tt)*) => {
+        c!{C_FN $m $id $lt
+           [$($ext)* _ : *const ::std::os::raw::c_char ,]
+           [$($wrap)* $name : & $($l)* str ,]
+           [$($call)*
+            ::std::ffi::CString::new($name)
+                .expect(concat!("`", stringify!($name), "` was passed to C with a nul byte inside it"))
+                .as_ptr() ,]
+           [wrap] ( $($args)* ) $($rest)*}
+    };
+
+    // So is a borrowed Vec, as a view of its elements
+    (C_FN $m:tt $id:ident $lt:tt [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] $mode:tt
+     ( $name:ident : & $($l:lifetime)* Vec < $et:ty > as ( $pct:tt , $lct:tt ) , $($args:tt)* ) $($rest:tt)*) => {
+        c!{C_FN $m $id $lt
+           [$($ext)* _ : *const $et , _ : usize ,]
+           [$($wrap)* $name : & $($l)* Vec < $et > ,]
+           [$($call)* $name.as_ptr() , $name.len() ,]
+           [wrap] ( $($args)* ) $($rest)*}
+    };
+
+    // So is a raw slice, which doesn't need to point to anything valid for
+    // its length to be taken
+    (C_FN $m:tt $id:ident $lt:tt [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] $mode:tt
+     ( $name:ident : *const [ $et:ty ] as ( $pct:tt , $lct:tt ) , $($args:tt)* ) $($rest:tt)*) => {
+        c!{C_FN $m $id $lt
+           [$($ext)* _ : *const $et , _ : usize ,]
+           [$($wrap)* $name : *const [ $et ] ,]
+           [$($call)* $name as *const $et , $name.len() ,]
+           [wrap] ( $($args)* ) $($rest)*}
+    };
+    (C_FN $m:tt $id:ident $lt:tt [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] $mode:tt
+     ( $name:ident : *mut [ $et:ty ] as ( $pct:tt , $lct:tt ) , $($args:tt)* ) $($rest:tt)*) => {
+        c!{C_FN $m $id $lt
+           [$($ext)* _ : *mut $et , _ : usize ,]
+           [$($wrap)* $name : *mut [ $et ] ,]
+           [$($call)* $name as *mut $et , $name.len() ,]
+           [wrap] ( $($args)* ) $($rest)*}
+    };
+
+    // A Vec which C pushes onto is passed to C as a pointer to it, and a
+    // thunk which pushes an element, given that pointer
+    (C_FN $m:tt $id:ident $lt:tt [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] $mode:tt
+     ( $name:ident : & $($l:lifetime)* mut Vec < $et:ty > as $ct:tt , $($args:tt)* ) $($rest:tt)*) => {
+        c!{C_FN $m $id $lt
+           [$($ext)* _ : *mut ::std::os::raw::c_void , _ : unsafe extern "C" fn(*mut ::std::os::raw::c_void, $et) ,]
+           [$($wrap)* $name : & $($l)* mut Vec < $et > ,]
+           [$($call)*
+            $name as *mut Vec<$et> as *mut ::std::os::raw::c_void ,
+            {
+                unsafe extern "C" fn thunk(v: *mut ::std::os::raw::c_void, x: $et) {
+                    (*(v as *mut Vec<$et>)).push(x)
+                }
+                thunk
+            } ,]
+           [wrap] ( $($args)* ) $($rest)*}
+    };
+
+    // A closure is passed to C as a pointer to it, and a thunk which calls
+    // it, given that pointer
+    (C_FN $m:tt $id:ident $lt:tt [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] $mode:tt
+     ( $name:ident : & $($l:lifetime)* $(dyn)* Fn () as "rust_c_fn" , $($args:tt)* ) $($rest:tt)*) => {
+        c!{C_FN $m $id $lt
+           [$($ext)* _ : *mut ::std::os::raw::c_void , _ : unsafe extern "C" fn(*mut ::std::os::raw::c_void) ,]
+           [$($wrap)* $name : & $($l)* dyn Fn() ,]
+           [$($call)*
+            &$name as *const &dyn Fn() as *mut ::std::os::raw::c_void ,
+            {
+                unsafe extern "C" fn thunk(f: *mut ::std::os::raw::c_void) {
+                    (*(f as *const &dyn Fn()))()
+                }
+                thunk
+            } ,]
+           [wrap] ( $($args)* ) $($rest)*}
+    };
+    (C_FN $m:tt $id:ident $lt:tt [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] $mode:tt
+     ( $name:ident : & $($l:lifetime)* mut $(dyn)* FnMut () as "rust_c_fn" , $($args:tt)* ) $($rest:tt)*) => {
+        c!{C_FN $m $id $lt
+           [$($ext)* _ : *mut ::std::os::raw::c_void , _ : unsafe extern "C" fn(*mut ::std::os::raw::c_void) ,]
+           [$($wrap)* mut $name : & $($l)* mut dyn FnMut() ,]
+           [$($call)*
+            &mut $name as *mut &mut dyn FnMut() as *mut ::std::os::raw::c_void ,
+            {
+                unsafe extern "C" fn thunk(f: *mut ::std::os::raw::c_void) {
+                    (*(f as *mut &mut dyn FnMut()))()
+                }
+                thunk
+            } ,]
+           [wrap] ( $($args)* ) $($rest)*}
+    };
+
+    // A tuple is passed to C as one argument per element
+    (C_FN $m:tt $id:ident $lt:tt [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] $mode:tt
+     ( $name:ident : ( $t0:ty , $t1:ty $(,)* ) as ( $ct0:tt , $ct1:tt $(,)* ) , $($args:tt)* ) $($rest:tt)*) => {
+        c!{C_FN $m $id $lt
+           [$($ext)* _ : $t0 , _ : $t1 ,]
+           [$($wrap)* $name : ( $t0 , $t1 ) ,]
+           [$($call)* $name.0 , $name.1 ,]
+           [wrap] ( $($args)* ) $($rest)*}
+    };
+    (C_FN $m:tt $id:ident $lt:tt [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] $mode:tt
+     ( $name:ident : ( $t0:ty , $t1:ty , $t2:ty $(,)* ) as ( $ct0:tt , $ct1:tt , $ct2:tt $(,)* ) , $($args:tt)* ) $($rest:tt)*) => {
+        c!{C_FN $m $id $lt
+           [$($ext)* _ : $t0 , _ : $t1 , _ : $t2 ,]
+           [$($wrap)* $name : ( $t0 , $t1 , $t2 ) ,]
+           [$($call)* $name.0 , $name.1 , $name.2 ,]
+           [wrap] ( $($args)* ) $($rest)*}
+    };
+    (C_FN $m:tt $id:ident $lt:tt [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] $mode:tt
+     ( $name:ident : ( $t0:ty , $t1:ty , $t2:ty , $t3:ty $(,)* ) as ( $ct0:tt , $ct1:tt , $ct2:tt , $ct3:tt $(,)* ) , $($args:tt)* ) $($rest:tt)*) => {
+        c!{C_FN $m $id $lt
+           [$($ext)* _ : $t0 , _ : $t1 , _ : $t2 , _ : $t3 ,]
+           [$($wrap)* $name : ( $t0 , $t1 , $t2 , $t3 ) ,]
+           [$($call)* $name.0 , $name.1 , $name.2 , $name.3 ,]
+           [wrap] ( $($args)* ) $($rest)*}
+    };
+
+    // On unix, a path is passed to C as a pointer to its bytes and a length
+    (C_FN $m:tt $id:ident $lt:tt [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] $mode:tt
+     ( $name:ident : & $($l:lifetime)* Path as ( $pct:tt , $lct:tt ) , $($args:tt)* ) $($rest:tt)*) => {
+        c!{C_FN $m $id $lt [$($ext)*] [$($wrap)*] [$($call)*] $mode
+           ( $name : & $($l)* ::std::path::Path as OS_STR ( $pct , $lct ) , $($args)* ) $($rest)*}
+    };
+    (C_FN $m:tt $id:ident $lt:tt [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] $mode:tt
+     ( $name:ident : & $($l:lifetime)* OsStr as ( $pct:tt , $lct:tt ) , $($args:tt)* ) $($rest:tt)*) => {
+        c!{C_FN $m $id $lt [$($ext)*] [$($wrap)*] [$($call)*] $mode
+           ( $name : & $($l)* ::std::ffi::OsStr as OS_STR ( $pct , $lct ) , $($args)* ) $($rest)*}
+    };
+    (C_FN $m:tt $id:ident $lt:tt [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] $mode:tt
+     ( $name:ident : $t:ty as OS_STR ( $pct:tt , $lct:tt ) , $($args:tt)* ) $($rest:tt)*) => {
+        // Elsewhere a path isn't bytes, and there's no other way to pass it
+        #[cfg(not(unix))]
+        compile_error!(concat!("`", stringify!($name), "` of the c! function `", stringify!($id),
+                               "` is a path, which can only be passed to C on unix"));
+        c!{C_FN $m $id $lt
+           [$($ext)* _ : *const u8 , _ : usize ,]
+           [$($wrap)* $name : $t ,]
+           [$($call)*
+            ::std::os::unix::ffi::OsStrExt::as_bytes(::std::convert::AsRef::<::std::ffi::OsStr>::as_ref($name)).as_ptr() ,
+            ::std::os::unix::ffi::OsStrExt::as_bytes(::std::convert::AsRef::<::std::ffi::OsStr>::as_ref($name)).len() ,]
+           [wrap] ( $($args)* ) $($rest)*}
+    };
+
+    // A pinned reference is passed to C as the reference it wraps, which C
+    // must not move out of
+    (C_FN $m:tt $id:ident $lt:tt [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] $mode:tt
+     ( $name:ident : Pin < & $($l:lifetime)* mut $t:ty > as $ct:tt , $($args:tt)* ) $($rest:tt)*) => {
+        c!{C_FN $m $id $lt
+           [$($ext)* $name : & $($l)* mut $t ,]
+           [$($wrap)* $name : ::std::pin::Pin < & $($l)* mut $t > ,]
+           [$($call)* ::std::pin::Pin::get_unchecked_mut($name) ,]
+           [wrap] ( $($args)* ) $($rest)*}
+    };
+
+    // Anything else is passed to C as it is
+    (C_FN $m:tt $id:ident $lt:tt [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] $mode:tt
+     ( $name:ident : $t:ty as $ct:tt , $($args:tt)* ) $($rest:tt)*) => {
+        c!{C_FN $m $id $lt
+           [$($ext)* $name : $t ,]
+           [$($wrap)* $name : $t ,]
+           [$($call)* $name ,]
+           $mode ( $($args)* ) $($rest)*}
+    };
+
+    // A Result<(), String> is returned as whether it succeeded, with the
+    // error written into a buffer, which is read back up to its first nul
+    (C_FN [$($m:tt)*] $id:ident [$($lt:tt)*] [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] $mode:tt
+     ( $(,)* ) -> Result < () , String > $body:tt $($rest:tt)*) => {
+        $($m)*
+        pub unsafe fn $id < $($lt)* > ( $($wrap)* ) -> Result<(), String>
+		{
+            extern "C"
+            {
+                fn $id < $($lt)* > ( $($ext)* err: *mut u8, err_len: usize ) -> bool ;
+            }
+
+            let mut err = [0u8; 256];
+            if $id ( $($call)* err.as_mut_ptr(), err.len() ) {
+                Ok(())
+            } else {
+                let len = err.iter().position(|&b| b == 0).unwrap_or(err.len());
+                Err(String::from_utf8_lossy(&err[..len]).into_owned())
+            }
+        }
+        c!{$($rest)*}
+    };
+
+    // A Result is returned through a pair of out-parameters, so the C
+    // function is wrapped in one which reassembles the Result
+    (C_FN [$($m:tt)*] $id:ident [$($lt:tt)*] [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] $mode:tt
+     ( $(,)* ) -> Result < $okt:ty as $okct:tt , $errt:ty as $errct:tt > $body:tt $($rest:tt)*) => {
+        $($m)*
+        pub unsafe fn $id < $($lt)* > ( $($wrap)* ) -> Result<$okt, $errt>
+		{
+            extern "C"
+            {
+                fn $id < $($lt)* > ( $($ext)* out_ok: *mut $okt, out_err: *mut $errt ) -> bool ;
+            }
+
+            let mut ok = ::std::mem::MaybeUninit::<$okt>::uninit();
+            let mut err = ::std::mem::MaybeUninit::<$errt>::uninit();
+            if $id ( $($call)* ok.as_mut_ptr(), err.as_mut_ptr() ) {
+                Ok(ok.assume_init())
+            } else {
+                Err(err.assume_init())
+            }
+        }
+        c!{$($rest)*}
+    };
+
+    // An io::Result<()> is returned as an errno, which is zero for success
+    (C_FN [$($m:tt)*] $id:ident [$($lt:tt)*] [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] $mode:tt
+     ( $(,)* ) -> io::Result<()> $body:tt $($rest:tt)*) => {
+        $($m)*
+        pub unsafe fn $id < $($lt)* > ( $($wrap)* ) -> ::std::io::Result<()>
+		{
+            extern "C"
+            {
+                fn $id < $($lt)* > ( $($ext)* ) -> ::std::os::raw::c_int ;
+            }
+
+            match $id ( $($call)* ) {
+                0 => Ok(()),
+                errno => Err(::std::io::Error::from_raw_os_error(errno)),
+            }
+        }
+        c!{$($rest)*}
+    };
+
+    // A Vec is returned as a malloc'd array, with its length written to an
+    // out-parameter. It is copied into the Vec, and then freed by the C code,
+    // which may not share rust's C library.
+    (C_FN [$($m:tt)*] $id:ident [$($lt:tt)*] [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] $mode:tt
+     ( $(,)* ) -> Vec < $t:ty as $ct:tt > $body:tt $($rest:tt)*) => {
+        $($m)*
+        pub unsafe fn $id < $($lt)* > ( $($wrap)* ) -> Vec<$t>
+		{
+            extern "C"
+            {
+                fn $id < $($lt)* > ( $($ext)* out_len: *mut usize ) -> *mut $t ;
+                #[link_name = concat!("rust_c_free_", stringify!($id))]
+                fn rust_c_free(ptr: *mut ::std::os::raw::c_void);
+            }
+
+            let mut len = 0;
+            let ptr = $id ( $($call)* &mut len );
+            if ptr.is_null() {
+                return Vec::new()
+            }
+
+            let vec = ::std::slice::from_raw_parts(ptr, len).to_vec();
+            rust_c_free(ptr as *mut ::std::os::raw::c_void);
+            vec
+        }
+        c!{$($rest)*}
+    };
+
+    // A function filling a buffer returns how much of it was written, which
+    // is returned to rust as that much of the buffer
+    (C_FN [$($m:tt)*] $id:ident [$($lt:tt)*] [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] $mode:tt
+     ( $(,)* ) -> & $($l:lifetime)* [ $t:ty ] in $buf:ident as $rct:tt $body:tt $($rest:tt)*) => {
+        $($m)*
+        pub unsafe fn $id < $($lt)* > ( $($wrap)* ) -> & $($l)* [ $t ]
+		{
+            extern "C"
+            {
+                fn $id < $($lt)* > ( $($ext)* ) -> usize ;
+            }
+
+            let written = $id ( $($call)* );
+            assert!(written <= $buf.len(),
+                    concat!("`", stringify!($id), "` wrote more than the length of `", stringify!($buf), "`"));
+            &$buf[..written]
+        }
+        c!{$($rest)*}
+    };
+
+    // A static C string is returned as a pointer, which must not be null
+    (C_FN [$($m:tt)*] $id:ident [$($lt:tt)*] [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] $mode:tt
+     ( $(,)* ) -> & 'static CStr as $rct:tt $body:tt $($rest:tt)*) => {
+        $($m)*
+        pub unsafe fn $id < $($lt)* > ( $($wrap)* ) -> &'static ::std::ffi::CStr
+		{
+            extern "C"
+            {
+                fn $id < $($lt)* > ( $($ext)* ) -> *const ::std::os::raw::c_char ;
+            }
+
+            let ptr = $id ( $($call)* );
+            assert!(!ptr.is_null(), concat!("`", stringify!($id), "` returned a null pointer as a &CStr"));
+            ::std::ffi::CStr::from_ptr(ptr)
+        }
+        c!{$($rest)*}
+    };
+
+    // A NonNull is returned as a pointer, which is checked for null in
+    // debug builds
+    (C_FN [$($m:tt)*] $id:ident [$($lt:tt)*] [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] $mode:tt
+     ( $(,)* ) -> NonNull < $t:ty > as $rct:tt $body:tt $($rest:tt)*) => {
+        $($m)*
+        pub unsafe fn $id < $($lt)* > ( $($wrap)* ) -> ::std::ptr::NonNull<$t>
+		{
+            extern "C"
+            {
+                fn $id < $($lt)* > ( $($ext)* ) -> *mut $t ;
+            }
+
+            let ptr = $id ( $($call)* );
+            debug_assert!(!ptr.is_null(), concat!("`", stringify!($id), "` returned a null pointer as a NonNull"));
+            ::std::ptr::NonNull::new_unchecked(ptr)
+        }
+        c!{$($rest)*}
+    };
+
+    // An array is returned inside a struct, as C can't return arrays
+    (C_FN [$($m:tt)*] $id:ident [$($lt:tt)*] [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] $mode:tt
+     ( $(,)* ) -> [ $et:ty ; $n:expr ] as $rct:tt $body:tt $($rest:tt)*) => {
+        $($m)*
+        pub unsafe fn $id < $($lt)* > ( $($wrap)* ) -> [ $et ; $n ]
+		{
+            #[repr(C)]
+            struct Out ( [ $et ; $n ] );
+
+            extern "C"
+            {
+                fn $id < $($lt)* > ( $($ext)* ) -> Out ;
+            }
+
+            $id ( $($call)* ).0
+        }
+        c!{$($rest)*}
+    };
+
+    // A tuple is returned through an out-parameter, pointing to a struct
+    // with a field for each element
+    (C_FN $m:tt $id:ident $lt:tt $ext:tt $wrap:tt $call:tt $mode:tt
+     ( $(,)* ) -> ( $t0:ty , $t1:ty $(,)* ) as ( $ct0:tt , $ct1:tt $(,)* ) $body:tt $($rest:tt)*) => {
+        c!{C_FN_TUPLE $m $id $lt $ext $wrap $call ( $t0 , $t1 ) ( 0 , 1 ) $($rest)*}
+    };
+    (C_FN $m:tt $id:ident $lt:tt $ext:tt $wrap:tt $call:tt $mode:tt
+     ( $(,)* ) -> ( $t0:ty , $t1:ty , $t2:ty $(,)* ) as ( $ct0:tt , $ct1:tt , $ct2:tt $(,)* ) $body:tt $($rest:tt)*) => {
+        c!{C_FN_TUPLE $m $id $lt $ext $wrap $call ( $t0 , $t1 , $t2 ) ( 0 , 1 , 2 ) $($rest)*}
+    };
+    (C_FN $m:tt $id:ident $lt:tt $ext:tt $wrap:tt $call:tt $mode:tt
+     ( $(,)* ) -> ( $t0:ty , $t1:ty , $t2:ty , $t3:ty $(,)* ) as ( $ct0:tt , $ct1:tt , $ct2:tt , $ct3:tt $(,)* ) $body:tt $($rest:tt)*) => {
+        c!{C_FN_TUPLE $m $id $lt $ext $wrap $call ( $t0 , $t1 , $t2 , $t3 ) ( 0 , 1 , 2 , 3 ) $($rest)*}
+    };
+    (C_FN_TUPLE [$($m:tt)*] $id:ident [$($lt:tt)*] [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*]
+     ( $($t:ty),* ) ( $($i:tt),* ) $($rest:tt)*) => {
+        $($m)*
+        pub unsafe fn $id < $($lt)* > ( $($wrap)* ) -> ( $($t ,)* )
+		{
+            #[repr(C)]
+            struct Out ( $($t ,)* );
+
+            extern "C"
+            {
+                fn $id < $($lt)* > ( $($ext)* out: *mut Out ) ;
+            }
+
+            let mut out = ::std::mem::MaybeUninit::<Out>::uninit();
+            $id ( $($call)* out.as_mut_ptr() );
+            let out = out.assume_init();
+            ( $(out.$i ,)* )
+        }
+        c!{$($rest)*}
+    };
+
+    // A function which never returns has no C type to give
+    (C_FN [$($m:tt)*] $id:ident [$($lt:tt)*] [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] []
+     ( $(,)* ) -> ! $body:tt $($rest:tt)*) => {
         extern "C"
 		{
-            $(#[$m])*
-            pub fn $id ( $($name : $t),* ) -> $rt ;
+            $($m)*
+            pub fn $id < $($lt)* > ( $($ext)* ) -> ! ;
         }
         c!{$($rest)*}
     };
-    ($(#[$m:meta])*
-     fn $id:ident ( $($name:ident : $t:ty as $ct:tt),* ) $body:tt $($rest:tt)*) => {
+    (C_FN [$($m:tt)*] $id:ident [$($lt:tt)*] [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] [wrap]
+     ( $(,)* ) -> ! $body:tt $($rest:tt)*) => {
+        $($m)*
+        pub unsafe fn $id < $($lt)* > ( $($wrap)* ) -> !
+		{
+            extern "C"
+            {
+                fn $id < $($lt)* > ( $($ext)* ) -> ! ;
+            }
+
+            $id ( $($call)* )
+        }
+        c!{$($rest)*}
+    };
+
+    // Otherwise the C function is declared directly, unless one of the
+    // arguments needed converting
+    (C_FN [$($m:tt)*] $id:ident [$($lt:tt)*] [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] []
+     ( $(,)* ) -> $rt:ty as $rct:tt $body:tt $($rest:tt)*) => {
+        extern "C"
+		{
+            $($m)*
+            pub fn $id < $($lt)* > ( $($ext)* ) -> $rt ;
+        }
+        c!{$($rest)*}
+    };
+    (C_FN [$($m:tt)*] $id:ident [$($lt:tt)*] [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] [wrap]
+     ( $(,)* ) -> $rt:ty as $rct:tt $body:tt $($rest:tt)*) => {
+        $($m)*
+        pub unsafe fn $id < $($lt)* > ( $($wrap)* ) -> $rt
+		{
+            extern "C"
+            {
+                fn $id < $($lt)* > ( $($ext)* ) -> $rt ;
+            }
+
+            $id ( $($call)* )
+        }
+        c!{$($rest)*}
+    };
+    (C_FN [$($m:tt)*] $id:ident [$($lt:tt)*] [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] []
+     ( $(,)* ) $body:tt $($rest:tt)*) => {
         extern "C"
 		{
-            $(#[$m])*
-            pub fn $id ( $($name : $t),* ) ;
+            $($m)*
+            pub fn $id < $($lt)* > ( $($ext)* ) ;
+        }
+        c!{$($rest)*}
+    };
+    (C_FN [$($m:tt)*] $id:ident [$($lt:tt)*] [$($ext:tt)*] [$($wrap:tt)*] [$($call:tt)*] [wrap]
+     ( $(,)* ) $body:tt $($rest:tt)*) => {
+        $($m)*
+        pub unsafe fn $id < $($lt)* > ( $($wrap)* )
+		{
+            extern "C"
+            {
+                fn $id < $($lt)* > ( $($ext)* ) ;
+            }
+
+            $id ( $($call)* )
+        }
+        c!{$($rest)*}
+    };
+
+    // Parse constant definition
+    ($(#[$m:meta])*
+     const $id:ident : $t:ty as $ct:tt = $e:expr ; $($rest:tt)*) => {
+        $(#[$m])*
+        const $id : $t = $e ;
+        c!{$($rest)*}
+    };
+
+    // Parse opaque type declarations, which only name a rust type to C
+    (opaque $id:ident ; $($rest:tt)*) => {c!{$($rest)*}};
+    ($(#[$m:meta])*
+     opaque $id:ident = $t:ty ; $($rest:tt)*) => {
+        $(#[$m])*
+        type $id = $t ;
+        c!{$($rest)*}
+    };
+
+    // Parse handle declarations, which own a pointer returned by C, and free
+    // it with the given function when dropped
+    ($(#[$m:meta])*
+     handle $id:ident = $free:ident ; $($rest:tt)*) => {
+        $(#[$m])*
+        struct $id(*mut ::std::os::raw::c_void);
+
+        impl $id {
+            // The pointer must be safe to pass to the free function, unless
+            // it is null
+            pub unsafe fn from_raw(ptr: *mut ::std::os::raw::c_void) -> Option<$id> {
+                if ptr.is_null() {
+                    None
+                } else {
+                    Some($id(ptr))
+                }
+            }
+
+            pub fn as_ptr(&self) -> *mut ::std::os::raw::c_void {
+                self.0
+            }
+
+            pub fn into_raw(self) -> *mut ::std::os::raw::c_void {
+                let ptr = self.0;
+                ::std::mem::forget(self);
+                ptr
+            }
+        }
+
+        impl Drop for $id {
+            fn drop(&mut self) {
+                unsafe { $free(self.0) }
+            }
         }
         c!{$($rest)*}
     };
@@ -53,14 +635,80 @@ macro_rules! c {
     };
 
     // Parse enum definition
-    ($(#[$m:meta])*
+    //
+    // The attributes are munched by the C_ENUM rules, so that a #[repr] given
+    // by the user replaces the default of #[repr(C)]
+    ($(#[$($a:tt)*])*
      enum $id:ident { $($i:ident ,)* } $($rest:tt)*) => {
-        $(#[$m])*
-        #[repr(C)]
+        c!{C_ENUM [] (C) ($(#[$($a)*])*) $id { $($i ,)* } $($rest)*}
+    };
+    (C_ENUM [$($m:tt)*] $r:tt (#[repr $repr:tt] $($a:tt)*) $($rest:tt)*) => {
+        c!{C_ENUM [$($m)*] $repr ($($a)*) $($rest)*}
+    };
+    (C_ENUM [$($m:tt)*] $r:tt (#[flags] $($a:tt)*) $($rest:tt)*) => {
+        c!{C_FLAGS [$($m)*] $r ($($a)*) $($rest)*}
+    };
+    (C_ENUM [$($m:tt)*] $r:tt (#[$($attr:tt)*] $($a:tt)*) $($rest:tt)*) => {
+        c!{C_ENUM [$($m)* #[$($attr)*]] $r ($($a)*) $($rest)*}
+    };
+    (C_ENUM [$($m:tt)*] $r:tt () $id:ident { $($i:ident ,)* } $($rest:tt)*) => {
+        $($m)*
+        #[repr $r]
         enum $id
 		{
             $($i ,)*
         }
         c!{$($rest)*}
     };
+
+    // An enum marked #[flags] is instead a wrapper around its integer repr,
+    // so that it may hold any combination of its variants. Each variant is a
+    // constant with the next bit set.
+    (C_FLAGS [$($m:tt)*] $r:tt (#[repr $repr:tt] $($a:tt)*) $($rest:tt)*) => {
+        c!{C_FLAGS [$($m)*] $repr ($($a)*) $($rest)*}
+    };
+    (C_FLAGS [$($m:tt)*] $r:tt (#[$($attr:tt)*] $($a:tt)*) $($rest:tt)*) => {
+        c!{C_FLAGS [$($m)* #[$($attr)*]] $r ($($a)*) $($rest)*}
+    };
+    (C_FLAGS [$($m:tt)*] ( $repr:ty ) () $id:ident { $($i:ident ,)* } $($rest:tt)*) => {
+        $($m)*
+        #[repr(transparent)]
+        struct $id(pub $repr);
+
+        impl $id {
+            c!{C_FLAGS_BITS $id [0] $($i ,)*}
+
+            pub fn contains(&self, other: $id) -> bool {
+                self.0 & other.0 == other.0
+            }
+        }
+
+        impl ::std::ops::BitOr for $id {
+            type Output = $id;
+            fn bitor(self, other: $id) -> $id {
+                $id(self.0 | other.0)
+            }
+        }
+
+        impl ::std::ops::BitAnd for $id {
+            type Output = $id;
+            fn bitand(self, other: $id) -> $id {
+                $id(self.0 & other.0)
+            }
+        }
+
+        impl ::std::ops::Not for $id {
+            type Output = $id;
+            fn not(self) -> $id {
+                $id(!self.0)
+            }
+        }
+        c!{$($rest)*}
+    };
+    (C_FLAGS_BITS $id:ident [$($n:tt)*] $i:ident , $($is:tt)*) => {
+        #[allow(non_upper_case_globals)]
+        pub const $i: $id = $id(1 << ($($n)*));
+        c!{C_FLAGS_BITS $id [$($n)* + 1] $($is)*}
+    };
+    (C_FLAGS_BITS $id:ident [$($n:tt)*]) => {};
 }
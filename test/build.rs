@@ -1,6 +1,25 @@
 extern crate c;
+extern crate cc;
 
 fn main() {
-    c::build("src/lib.rs", "c_test", |cfg| {
-    });
+    // A library which the C code depends on, so which must be linked after it
+    cc::Build::new()
+        .file("src/square.c")
+        .cargo_metadata(false)
+        .compile("c_test_square");
+    println!("cargo:rerun-if-changed=src/square.c");
+
+    c::Config::new()
+        .header("// SPDX-License-Identifier: MIT")
+        .target_feature("sse4.1")
+        .build_info(true)
+        .whole_archive(true)
+        .link_after("static=c_test_square")
+        .min_gcc_version(4, 7)
+        .min_clang_version(3, 1)
+        .build("src/lib.rs", "c_test", |cfg| {
+            cfg.define("C_TEST_DEFINE", Some("42"));
+            cfg.include("src");
+            cfg.flag_if_supported("-Werror=unused-parameter");
+        });
 }
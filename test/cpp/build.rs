@@ -0,0 +1,12 @@
+extern crate c;
+
+fn main() {
+    // The generated code compiled as C++, which it must be valid as too
+    c::Config::new()
+        .extension("cpp")
+        .build("src/lib.rs", "c_test_cpp", |cfg| {
+            cfg.cpp(true);
+            cfg.flag_if_supported("-std=c++17");
+            cfg.flag_if_supported("-Werror=unused-parameter");
+        });
+}
@@ -0,0 +1,129 @@
+#![cfg_attr(not(test), allow(dead_code))]
+
+#[macro_use]
+extern crate c;
+
+c! {
+    #include <stdio.h>
+
+    #[derive(PartialEq, Eq, Debug, Copy, Clone)]
+    #[repr(u8)]
+    enum Level {
+        Low,
+        High,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Copy, Clone)]
+    #[flags]
+    #[repr(u32)]
+    enum Access {
+        Read,
+        Write,
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Range {
+        min: u32 as "uint32_t",
+        max: u32 as "uint32_t",
+    }
+
+    fn level_flip(level: Level as "Level") -> Level as "Level" {
+        return level == Low ? High : Low;
+    }
+
+    fn read_write() -> Access as "Access" {
+        return Read | Write;
+    }
+
+    fn same_range(a: &Range as "const Range *", b: &Range as "const Range *") -> bool as "bool_" {
+        return *a == *b;
+    }
+
+    fn checked_divide(a: u32 as "uint32_t", b: u32 as "uint32_t")
+                      -> Result<u32 as "uint32_t", i32 as "int32_t"> {
+        if (b == 0) {
+            *out_err = -1;
+            return false;
+        }
+        *out_ok = a / b;
+        return true;
+    }
+
+    fn parse_digit(c: u8 as "uint8_t") -> Result<(), String> {
+        if (c < '0' || c > '9') {
+            snprintf(err, err_len, "'%c' is not a digit", c);
+            return false;
+        }
+        return true;
+    }
+
+    fn comma(s: &str as ("const char *", "size_t")) -> usize as "size_t" {
+        return RUST_C_STRING_VIEW(s).find(',');
+    }
+
+    #[always_inline]
+    fn increment(x: i32 as "int32_t") -> i32 as "int32_t" {
+        return x + 1;
+    }
+
+    fn push_squares(v: &mut Vec<u32> as "uint32_t", n: u32 as "uint32_t") {
+        for (uint32_t i = 1; i <= n; i++) {
+            RUST_C_PUSH(v, i * i);
+        }
+    }
+}
+
+#[test]
+fn repr_enum() {
+    unsafe {
+        assert_eq!(level_flip(Level::Low), Level::High);
+    }
+}
+
+#[test]
+fn flags_enum() {
+    unsafe {
+        assert_eq!(read_write(), Access::Read | Access::Write);
+    }
+}
+
+#[test]
+fn struct_equality() {
+    let a = Range { min: 1, max: 2 };
+    let b = Range { min: 1, max: 3 };
+    unsafe {
+        assert!(same_range(&a, &a));
+        assert!(!same_range(&a, &b));
+    }
+}
+
+#[test]
+fn result_return() {
+    unsafe {
+        assert_eq!(checked_divide(10, 2), Ok(5));
+        assert_eq!(checked_divide(10, 0), Err(-1));
+        assert_eq!(parse_digit(b'7'), Ok(()));
+        assert_eq!(parse_digit(b'a'), Err("'a' is not a digit".to_owned()));
+    }
+}
+
+#[test]
+fn string_view() {
+    unsafe {
+        assert_eq!(comma("a,b"), 1);
+    }
+}
+
+#[test]
+fn always_inline() {
+    unsafe {
+        assert_eq!(increment(1), 2);
+    }
+}
+
+#[test]
+fn vec_push() {
+    let mut squares = Vec::new();
+    unsafe { push_squares(&mut squares, 3) };
+    assert_eq!(squares, [1, 4, 9]);
+}
@@ -0,0 +1,6 @@
+#ifndef C_TEST_ANSWER_H
+#define C_TEST_ANSWER_H
+
+#define C_TEST_ANSWER 42
+
+#endif
@@ -0,0 +1,10 @@
+#include <stdint.h>
+
+void mark_initialized(void);
+
+/* Nothing refers to this file, so it is only linked with whole_archive */
+#if defined(__GNUC__)
+__attribute__((constructor)) static void initialize(void) {
+    mark_initialized();
+}
+#endif
@@ -0,0 +1,10 @@
+#ifndef C_TEST_INTERVAL_PAIR_H
+#define C_TEST_INTERVAL_PAIR_H
+
+/* Uses Interval, which rust-c defines, so must be included after it */
+typedef struct {
+    Interval first;
+    Interval second;
+} IntervalPair;
+
+#endif
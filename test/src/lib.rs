@@ -167,3 +167,1458 @@ fn string_body() {
         assert_eq!(string_body_impl(), 10);
     }
 }
+
+c! {
+    #[derive(Copy, Clone)]
+    struct Point {
+        x: i32 as "int32_t",
+        y: i64 as "int64_t",
+    }
+
+    fn point_stride_impl() -> usize as "size_t" {
+        return sizeof(struct Point);
+    }
+
+    fn point_sum_impl(points: &[Point] as ("const struct Point *", "size_t"))
+                      -> i64 as "int64_t" {
+        int64_t sum = 0;
+        size_t i;
+        for (i = 0; i < points_len; i++) {
+            sum += points[i].x * points[i].y;
+        }
+        return sum;
+    }
+}
+
+#[test]
+fn array_of_struct() {
+    let points = [
+        Point { x: 1, y: 10 },
+        Point { x: 2, y: 20 },
+        Point { x: 3, y: 30 },
+        Point { x: 4, y: 40 },
+    ];
+    let slice: &[Point] = &points;
+
+    unsafe {
+        assert_eq!(point_stride_impl(), ::std::mem::size_of::<Point>());
+        assert_eq!(point_sum_impl(slice), 300);
+        assert_eq!(point_sum_impl(&points[2..]), 250);
+    }
+}
+
+c! {
+    fn borrowed_return_impl<'a>(data: &'a [u8; 5] as "const uint8_t *",
+                                index: usize as "size_t")
+                                -> &'a u8 as "const uint8_t *" {
+        return &data[index];
+    }
+}
+
+#[test]
+fn borrowed_return() {
+    let data = [1u8, 2, 3, 4, 5];
+
+    let middle: &u8 = unsafe {
+        borrowed_return_impl(&data, 2)
+    };
+
+    assert_eq!(*middle, 3);
+    assert_eq!(middle as *const u8, &data[2] as *const u8);
+}
+
+c! {
+    fn checked_divide_impl(a: u32 as "uint32_t", b: u32 as "uint32_t")
+                           -> Result<u32 as "uint32_t", i32 as "int32_t"> {
+        if (b == 0) {
+            *out_err = -1;
+            return 0;
+        }
+
+        *out_ok = a / b;
+        return 1;
+    }
+}
+
+#[test]
+fn result_return() {
+    unsafe {
+        assert_eq!(checked_divide_impl(10, 2), Ok(5));
+        assert_eq!(checked_divide_impl(10, 0), Err(-1));
+    }
+}
+
+c! {
+    raw {
+        static uint32_t checksum(const uint8_t *data, size_t len) {
+            uint32_t sum = 0;
+            size_t i;
+            for (i = 0; i < len; i++) {
+                sum = sum * 31 + data[i];
+            }
+            return sum;
+        }
+    }
+
+    fn checksum_impl(data: &[u8] as ("const uint8_t *", "size_t")) -> u32 as "uint32_t" {
+        return checksum(data, data_len);
+    }
+
+    fn fill_impl(value: u8 as "uint8_t", buffer: &mut [u8] as ("uint8_t *", "size_t")) {
+        size_t i;
+        for (i = 0; i < buffer_len; i++) {
+            buffer[i] = value;
+        }
+    }
+}
+
+#[test]
+fn slice_as_pointer_and_length() {
+    let data = b"Hello, World!";
+    let expected = data.iter().fold(0u32, |sum, &b| sum.wrapping_mul(31).wrapping_add(b as u32));
+
+    let mut buffer = [0u8; 4];
+
+    unsafe {
+        assert_eq!(checksum_impl(&data[..]), expected);
+        assert_eq!(checksum_impl(&data[..0]), 0);
+
+        fill_impl(7, &mut buffer);
+    }
+
+    assert_eq!(buffer, [7, 7, 7, 7]);
+}
+
+#[cfg(unix)]
+c! {
+    fn path_components_impl(path: &Path as ("const char *", "size_t")) -> usize as "size_t" {
+        size_t components = 0;
+        size_t i;
+        for (i = 0; i < path_len; i++) {
+            if (path[i] == '/') {
+                components++;
+            }
+        }
+        return components;
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn path_as_bytes() {
+    use std::path::Path;
+
+    unsafe {
+        assert_eq!(path_components_impl(Path::new("/usr/local/lib")), 3);
+        assert_eq!(path_components_impl(Path::new("relative")), 0);
+    }
+}
+
+c! {
+    source "src/triple.c"
+
+    raw {
+        int32_t triple(int32_t x);
+    }
+
+    fn source_file_impl(x: i32 as "int32_t") -> i32 as "int32_t" {
+        return triple(x) + 1;
+    }
+}
+
+#[test]
+fn source_file() {
+    unsafe {
+        assert_eq!(source_file_impl(5), 16);
+    }
+}
+
+c! {
+    #[derive(PartialEq, Eq, Debug, Copy, Clone)]
+    #[repr(u8)]
+    enum Level {
+        Low,
+        High,
+    }
+
+    fn level_size_impl() -> usize as "size_t" {
+        return sizeof(Level);
+    }
+
+    fn level_flip_impl(level: Level as "Level") -> Level as "Level" {
+        return level == Low ? High : Low;
+    }
+}
+
+#[test]
+fn repr_enum() {
+    assert_eq!(::std::mem::size_of::<Level>(), 1);
+
+    unsafe {
+        assert_eq!(level_size_impl(), 1);
+        assert_eq!(level_flip_impl(Level::Low), Level::High);
+        assert_eq!(level_flip_impl(Level::High), Level::Low);
+    }
+}
+
+c! {
+    /// A value, which only counts when its kind is `High`
+    struct Tagged {
+        kind: Level as "Level",
+        value: u32 as "uint32_t",
+    }
+
+    fn tagged_value(tagged: *const Tagged as "const Tagged *") -> u32 as "uint32_t" {
+        return tagged->kind == High ? tagged->value : 0;
+    }
+}
+
+#[test]
+fn enum_field() {
+    let low = Tagged { kind: Level::Low, value: 7 };
+    let high = Tagged { kind: Level::High, value: 7 };
+    unsafe {
+        assert_eq!(tagged_value(&low), 0);
+        assert_eq!(tagged_value(&high), 7);
+    }
+}
+
+c! {
+    #[derive(PartialEq, Eq, Debug, Copy, Clone)]
+    #[flags]
+    #[repr(u32)]
+    enum Access {
+        Read,
+        Write,
+        Execute,
+    }
+
+    fn read_write() -> Access as "Access" {
+        return Read | Write;
+    }
+
+    fn can_execute(access: Access as "Access") -> bool as "bool_" {
+        return (access & Execute) != 0;
+    }
+}
+
+#[test]
+fn flags_enum() {
+    assert_eq!(::std::mem::size_of::<Access>(), 4);
+    assert_eq!(Access::Execute.0, 4);
+
+    unsafe {
+        assert_eq!(read_write(), Access::Read | Access::Write);
+        assert!(read_write().contains(Access::Write));
+        assert!(!read_write().contains(Access::Execute));
+        assert!(can_execute(Access::Read | Access::Execute));
+        assert!(!can_execute(read_write() & !Access::Read));
+    }
+}
+
+c! {
+    #include <stdlib.h>
+
+    fn squares_impl(n: u32 as "uint32_t") -> Vec<u32 as "uint32_t"> {
+        uint32_t *squares;
+        uint32_t i;
+
+        if (n == 0) {
+            *out_len = 0;
+            return NULL;
+        }
+
+        squares = malloc(n * sizeof(uint32_t));
+        for (i = 0; i < n; i++) {
+            squares[i] = i * i;
+        }
+
+        *out_len = n;
+        return squares;
+    }
+}
+
+#[test]
+fn vec_return() {
+    unsafe {
+        assert_eq!(squares_impl(5), vec![0, 1, 4, 9, 16]);
+        assert_eq!(squares_impl(0), Vec::<u32>::new());
+    }
+}
+
+c! {
+    #include <stdlib.h>
+    #include "rust_types.h"
+
+    fn alloc_bytes(n: usize as "size_t", out: &mut *mut u8 as "uint8_t **") -> bool as "bool_" {
+        size_t i;
+
+        *out = malloc(n);
+        if (*out == NULL) {
+            return 0;
+        }
+
+        for (i = 0; i < n; i++) {
+            (*out)[i] = (uint8_t)i;
+        }
+        return 1;
+    }
+
+    fn free_bytes(bytes: *mut u8 as "uint8_t *") {
+        free(bytes);
+    }
+}
+
+#[test]
+fn out_pointer() {
+    let mut bytes: *mut u8 = ::std::ptr::null_mut();
+    unsafe {
+        assert!(alloc_bytes(4, &mut bytes));
+        assert!(!bytes.is_null());
+        assert_eq!(::std::slice::from_raw_parts(bytes, 4), &[0, 1, 2, 3]);
+        free_bytes(bytes);
+    }
+}
+
+c! {
+    source "src/initializer.c"
+
+    raw {
+        static uint32_t initialized;
+    }
+
+    fn mark_initialized() {
+        initialized = 1;
+    }
+
+    fn is_initialized() -> u32 as "uint32_t" {
+    #if defined(__GNUC__)
+        return initialized;
+    #else
+        return 1;
+    #endif
+    }
+}
+
+#[test]
+fn static_initializer() {
+    unsafe {
+        assert_eq!(is_initialized(), 1);
+    }
+}
+
+c! {
+    fn build_define() -> u32 as "uint32_t" {
+        return C_TEST_DEFINE;
+    }
+}
+
+#[test]
+fn configured_build() {
+    unsafe {
+        assert_eq!(build_define(), 42);
+    }
+}
+
+c! {
+    #include <stdlib.h>
+
+    raw {
+        struct counter {
+            uint32_t count;
+        };
+
+        static uint32_t counters_alive;
+    }
+
+    handle Counter = counter_free;
+
+    fn counter_new() -> *mut ::std::os::raw::c_void as "void *" {
+        struct counter *counter = malloc(sizeof(struct counter));
+        if (counter != NULL) {
+            counter->count = 0;
+            counters_alive++;
+        }
+        return counter;
+    }
+
+    fn counter_add(counter: *mut ::std::os::raw::c_void as "void *", n: u32 as "uint32_t") -> u32 as "uint32_t" {
+        return ((struct counter *)counter)->count += n;
+    }
+
+    fn counter_free(counter: *mut ::std::os::raw::c_void as "void *") {
+        free(counter);
+        counters_alive--;
+    }
+
+    fn live_counters() -> u32 as "uint32_t" {
+        return counters_alive;
+    }
+}
+
+#[test]
+fn handle() {
+    unsafe {
+        let counter = Counter::from_raw(counter_new()).unwrap();
+        assert_eq!(live_counters(), 1);
+        assert_eq!(counter_add(counter.as_ptr(), 2), 2);
+        assert_eq!(counter_add(counter.as_ptr(), 3), 5);
+        drop(counter);
+        assert_eq!(live_counters(), 0);
+
+        assert!(Counter::from_raw(::std::ptr::null_mut()).is_none());
+    }
+}
+
+c! {
+    #include <string.h>
+
+    fn greet<'a>(name: &str as ("const uint8_t *", "size_t"),
+                 buf: &'a mut [u8] as ("uint8_t *", "size_t")) -> &'a [u8] in buf as "size_t" {
+        size_t n = 0;
+
+        if (n + 6 <= buf_len) {
+            memcpy(buf + n, "hello ", 6);
+            n += 6;
+        }
+        if (n + name_len <= buf_len) {
+            memcpy(buf + n, name, name_len);
+            n += name_len;
+        }
+        return n;
+    }
+
+    fn overfill(buf: &mut [u8] as ("uint8_t *", "size_t")) -> &[u8] in buf as "size_t" {
+        return buf_len + 1;
+    }
+}
+
+#[test]
+fn filled_buffer() {
+    let mut buf = [0; 16];
+    unsafe {
+        assert_eq!(greet("world", &mut buf), b"hello world");
+        assert_eq!(greet("world", &mut buf[..8]), b"hello ");
+        assert_eq!(greet("world", &mut []), b"");
+    }
+}
+
+#[test]
+#[should_panic(expected = "`overfill` wrote more than the length of `buf`")]
+fn overfilled_buffer() {
+    unsafe {
+        overfill(&mut [0; 4]);
+    }
+}
+
+c! {
+    #[variant(widest_vector_avx2 = "avx2")]
+    fn widest_vector() -> u32 as "uint32_t" {
+    #if defined(__AVX2__)
+        return 256;
+    #else
+        return 128;
+    #endif
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[test]
+fn function_variant() {
+    let widest = if is_x86_feature_detected!("avx2") {
+        unsafe { widest_vector_avx2() }
+    } else {
+        unsafe { widest_vector() }
+    };
+
+    unsafe {
+        assert_eq!(widest_vector(), 128);
+    }
+    if is_x86_feature_detected!("avx2") {
+        assert_eq!(widest, 256);
+    } else {
+        assert_eq!(widest, 128);
+    }
+}
+
+c! {
+    fn rust_location() -> &'static CStr as "const char *" {
+        return RUST_C_LOC;
+    }
+}
+
+#[test]
+fn source_location() {
+    let line = include_str!("lib.rs")
+        .lines()
+        .position(|line| line.trim() == "return RUST_C_LOC;")
+        .unwrap() + 1;
+
+    let location = unsafe { rust_location() }.to_str().unwrap();
+    assert!(location.ends_with(&format!("lib.rs:{}", line)), "{}", location);
+}
+
+static HITS: ::std::sync::atomic::AtomicU32 = ::std::sync::atomic::AtomicU32::new(0);
+
+c! {
+    #include <stdatomic.h>
+
+    raw {
+        static _Atomic uint32_t *hits;
+    }
+
+    fn count_hits_in(counter: &'static ::std::sync::atomic::AtomicU32 as "_Atomic uint32_t *") {
+        hits = counter;
+    }
+
+    fn hit() {
+        atomic_fetch_add(hits, 1);
+    }
+}
+
+#[test]
+fn static_atomic() {
+    unsafe {
+        count_hits_in(&HITS);
+        let threads: Vec<_> = (0..4)
+            .map(|_| ::std::thread::spawn(|| for _ in 0..100 { hit() }))
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+    assert_eq!(HITS.load(::std::sync::atomic::Ordering::SeqCst), 400);
+}
+
+c! {
+    fn tuple_argument_impl(point: (i32, i32) as ("int32_t", "int32_t"),
+                           scale: i32 as "int32_t")
+                           -> i32 as "int32_t" {
+        return (point_0 - point_1) * scale;
+    }
+}
+
+#[test]
+fn tuple_argument() {
+    unsafe {
+        assert_eq!(tuple_argument_impl((7, 3), 2), 8);
+    }
+}
+
+c! {
+    #include <stdlib.h>
+
+    fn abort_impl() -> ! {
+        abort();
+    }
+}
+
+#[test]
+fn never_return() {
+    // Calling abort_impl would take the test harness down with it, so this
+    // test calls it in a copy of itself
+    if std::env::var_os("C_TEST_NEVER_RETURN").is_some() {
+        let abort: unsafe extern "C" fn() -> ! = abort_impl;
+        unsafe { abort() }
+    }
+
+    let status = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(&["never_return", "--exact"])
+        .env("C_TEST_NEVER_RETURN", "1")
+        .status()
+        .unwrap();
+    assert!(!status.success());
+}
+
+c! {
+    const BUFFER_SIZE: usize as "size_t" = 16;
+    const OFFSET: i32 as "int32_t" = -3;
+    const RATIO: f64 as "double" = 2.5;
+
+    fn shared_constant_impl() -> usize as "size_t" {
+        char buffer[BUFFER_SIZE];
+        return sizeof(buffer);
+    }
+
+    fn shared_constant_value_impl(x: f64 as "double") -> f64 as "double" {
+        return x * RATIO + OFFSET;
+    }
+}
+
+#[test]
+fn shared_constant() {
+    unsafe {
+        assert_eq!(shared_constant_impl(), BUFFER_SIZE);
+        assert_eq!(shared_constant_value_impl(2.0), 2.0 * RATIO + OFFSET as f64);
+    }
+}
+
+c! {
+    #[stub]
+    fn stubbed_impl(x: i32 as "int32_t") -> i32 as "int32_t" {
+        return this_function_does_not_exist(x);
+    }
+}
+
+// The body is never compiled, and the weak definition which aborts is
+// replaced by the one in square.c
+#[test]
+#[cfg(not(target_env = "msvc"))]
+fn stub() {
+    unsafe {
+        assert_eq!(stubbed_impl(3), -3);
+    }
+}
+
+#[cfg(unix)]
+c! {
+    #include <errno.h>
+    #include <fcntl.h>
+    #include <string.h>
+    #include <unistd.h>
+
+    fn open_and_close_impl(path: &Path as ("const char *", "size_t")) -> io::Result<()> {
+        char terminated[256];
+        int fd;
+
+        if (path_len >= sizeof(terminated)) {
+            return ENAMETOOLONG;
+        }
+        memcpy(terminated, path, path_len);
+        terminated[path_len] = '\0';
+
+        fd = open(terminated, O_RDONLY);
+        if (fd < 0) {
+            return errno;
+        }
+        close(fd);
+        return 0;
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn io_result_return() {
+    use std::io::ErrorKind;
+    use std::path::Path;
+
+    unsafe {
+        assert!(open_and_close_impl(Path::new("/")).is_ok());
+
+        let err = open_and_close_impl(Path::new("/this/path/does/not/exist")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+}
+
+#[test]
+fn custom_header() {
+    let code = include_str!(concat!(env!("OUT_DIR"), "/c_test.c"));
+    assert!(code.starts_with("// SPDX-License-Identifier: MIT\n// This code was machine-generated"));
+}
+
+c! {
+    fn raw_slice_len(data: *const [u8] as ("const uint8_t *", "size_t")) -> usize as "size_t" {
+        return data_len;
+    }
+
+    fn raw_slice_fill(value: u8 as "uint8_t", buffer: *mut [u8] as ("uint8_t *", "size_t")) {
+        size_t i;
+        for (i = 0; i < buffer_len; i++) {
+            buffer[i] = value;
+        }
+    }
+}
+
+#[test]
+fn raw_slice_as_pointer_and_length() {
+    use std::ptr;
+
+    let mut buffer = [0u8; 4];
+    unsafe {
+        assert_eq!(raw_slice_len(ptr::slice_from_raw_parts(buffer.as_ptr(), 3)), 3);
+
+        raw_slice_fill(7, ptr::slice_from_raw_parts_mut(buffer.as_mut_ptr(), 3));
+    }
+    assert_eq!(buffer, [7, 7, 7, 0]);
+}
+
+c! {
+    fn level_name(level: u32 as "uint32_t") -> *const ::std::os::raw::c_char as "const char *" {
+        static const char *names[] = { "low", "medium", "high" };
+        return level < 3 ? names[level] : NULL;
+    }
+}
+
+#[test]
+fn c_string_return() {
+    use std::ffi::CStr;
+
+    unsafe {
+        assert_eq!(CStr::from_ptr(level_name(1)).to_str(), Ok("medium"));
+        assert!(level_name(3).is_null());
+    }
+}
+
+c! {
+    fn sum_vec(values: &Vec<u32> as ("const uint32_t *", "size_t")) -> u32 as "uint32_t" {
+        uint32_t sum = 0;
+        size_t i;
+        for (i = 0; i < values_len; i++) {
+            sum += values[i];
+        }
+        return sum;
+    }
+}
+
+#[test]
+fn vec_as_slice() {
+    let values = vec![1, 2, 3, 4];
+    unsafe {
+        assert_eq!(sum_vec(&values), 10);
+        assert_eq!(sum_vec(&Vec::new()), 0);
+    }
+}
+
+c! {
+    struct Parent {
+        child: *mut Child as "Child *",
+        id: u32 as "uint32_t",
+    }
+
+    struct Child {
+        parent: *mut Parent as "Parent *",
+        id: u32 as "uint32_t",
+    }
+
+    fn grandparent_id(child: *const Child as "const Child *") -> u32 as "uint32_t" {
+        return child->parent->child->parent->id;
+    }
+}
+
+#[test]
+fn mutually_referential_structs() {
+    use std::ptr;
+
+    // The cycle is built through raw pointers, as a reference to either one
+    // would invalidate the pointer the other holds
+    let mut parent = Parent { child: ptr::null_mut(), id: 1 };
+    let p = &mut parent as *mut Parent;
+    let mut child = Child { parent: p, id: 2 };
+    let c = &mut child as *mut Child;
+    unsafe {
+        (*p).child = c;
+        assert_eq!(grandparent_id(c), 1);
+    }
+}
+
+c! {
+    raw {
+        #if defined(__SSE4_1__)
+        #include <smmintrin.h>
+        #endif
+    }
+
+    fn multiply_lanes(x: i32 as "int32_t", y: i32 as "int32_t") -> i32 as "int32_t" {
+    #if defined(__SSE4_1__)
+        __m128i product = _mm_mullo_epi32(_mm_set1_epi32(x), _mm_set1_epi32(y));
+        return _mm_cvtsi128_si32(product);
+    #else
+        return x * y;
+    #endif
+    }
+
+    fn has_sse4_1() -> bool as "bool_" {
+    #if defined(__SSE4_1__)
+        return 1;
+    #else
+        return 0;
+    #endif
+    }
+}
+
+#[test]
+fn target_feature() {
+    if cfg!(any(target_arch = "x86", target_arch = "x86_64")) {
+        unsafe {
+            assert!(has_sse4_1());
+        }
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if !is_x86_feature_detected!("sse4.1") {
+            return
+        }
+    }
+
+    unsafe {
+        assert_eq!(multiply_lanes(6, 7), 42);
+    }
+}
+
+#[cfg(never)]
+c! {
+    fn never_generated() {
+        #error "this block is behind a cfg which is never enabled"
+    }
+}
+
+#[cfg(feature = "disabled")]
+c! {
+    fn disabled_feature() {
+        #error "this block is behind a feature which is not enabled"
+    }
+}
+
+#[cfg(feature = "enabled")]
+c! {
+    fn enabled_feature() -> u32 as "uint32_t" {
+        return 1;
+    }
+}
+
+#[cfg(target_pointer_width = "64")]
+c! {
+    fn pointer_width() -> u32 as "uint32_t" {
+        return sizeof(void *) * 8;
+    }
+}
+
+#[cfg(target_pointer_width = "32")]
+c! {
+    fn pointer_width() -> u32 as "uint32_t" {
+        return sizeof(void *) * 8;
+    }
+}
+
+#[test]
+fn cfg_blocks() {
+    unsafe {
+        assert_eq!(pointer_width() as usize, ::std::mem::size_of::<usize>() * 8);
+    }
+}
+
+#[cfg(feature = "enabled")]
+#[test]
+fn feature_blocks() {
+    unsafe {
+        assert_eq!(enabled_feature(), 1);
+    }
+}
+
+c! {
+    fn debug_checked(x: i32 as "int32_t") -> bool as "bool_" {
+        RUST_C_DEBUG_ASSERT(x >= 0);
+    #ifndef NDEBUG
+        return 1;
+    #else
+        return 0;
+    #endif
+    }
+}
+
+#[test]
+fn debug_assertions() {
+    unsafe {
+        assert_eq!(debug_checked(1), cfg!(debug_assertions));
+    }
+}
+
+c! {
+    #include <stdio.h>
+
+    fn format_greeting(name: &str as "const char *", buffer: &mut [u8] as ("char *", "size_t")) -> usize as "size_t" {
+        return snprintf(buffer, buffer_len, "Hello, %s!", name);
+    }
+
+    fn str_length(s: &str as ("const char *", "size_t")) -> usize as "size_t" {
+        return s_len;
+    }
+}
+
+#[test]
+fn str_as_c_string() {
+    let mut buffer = [0u8; 32];
+    let len = unsafe { format_greeting("World", &mut buffer) };
+    assert_eq!(&buffer[..len], b"Hello, World!");
+
+    unsafe {
+        assert_eq!(str_length("caf\u{e9}"), 5);
+    }
+}
+
+#[test]
+#[should_panic(expected = "nul byte")]
+fn str_with_nul_as_c_string() {
+    let mut buffer = [0u8; 32];
+    unsafe {
+        format_greeting("Wor\0ld", &mut buffer);
+    }
+}
+
+c! {
+    fn divide_with_remainder(a: u32 as "uint32_t", b: u32 as "uint32_t") -> (u32, f64) as ("uint32_t", "double") {
+        out->_0 = a / b;
+        out->_1 = (double)(a % b) / b;
+    }
+
+    fn min_max_sum(values: &[i32] as ("const int32_t *", "size_t")) -> (i32, i32, i64) as ("int32_t", "int32_t", "int64_t") {
+        size_t i;
+        out->_0 = INT32_MAX;
+        out->_1 = INT32_MIN;
+        out->_2 = 0;
+        for (i = 0; i < values_len; i++) {
+            if (values[i] < out->_0) out->_0 = values[i];
+            if (values[i] > out->_1) out->_1 = values[i];
+            out->_2 += values[i];
+        }
+    }
+}
+
+#[test]
+fn tuple_return() {
+    unsafe {
+        assert_eq!(divide_with_remainder(7, 2), (3, 0.5));
+        assert_eq!(min_max_sum(&[3, -1, 4, 1, 5]), (-1, 5, 12));
+    }
+}
+
+c! {
+    fn static_greeting() -> &'static CStr as "const char *" {
+        return "Hello from C";
+    }
+}
+
+#[test]
+fn static_c_str_return() {
+    unsafe {
+        assert_eq!(static_greeting().to_str(), Ok("Hello from C"));
+    }
+}
+
+c! {
+    #[out_of_line]
+    fn out_of_line_triple(x: i32 as "int32_t") -> i32 as "int32_t" {
+        return basic_math_impl(x, x) / 10;
+    }
+}
+
+#[test]
+fn out_of_line() {
+    unsafe {
+        assert_eq!(out_of_line_triple(2), 6);
+    }
+}
+
+c! {
+    fn count_true(flags: &[bool] as ("const bool_ *", "size_t")) -> usize as "size_t" {
+        size_t count = 0;
+        size_t i;
+        for (i = 0; i < flags_len; i++) {
+            if (flags[i]) {
+                count++;
+            }
+        }
+        return count;
+    }
+}
+
+#[test]
+fn bool_slice() {
+    let flags = [true, false, true, true, false];
+    unsafe {
+        assert_eq!(count_true(&flags), flags.iter().filter(|&&flag| flag).count());
+    }
+}
+
+c! {
+    opaque Registry = ::std::collections::HashMap<String, u32>;
+
+    fn pick_registry(first: *mut Registry as "Registry *", second: *mut Registry as "Registry *", pick_first: bool as "bool_") -> *mut Registry as "Registry *" {
+        return pick_first ? first : second;
+    }
+}
+
+#[test]
+fn opaque_handle() {
+    let mut first = Registry::new();
+    first.insert("one".to_owned(), 1);
+    let mut second = Registry::new();
+    second.insert("two".to_owned(), 2);
+
+    unsafe {
+        let picked = pick_registry(&mut first, &mut second, false);
+        assert_eq!((*picked).get("two"), Some(&2));
+    }
+}
+
+c! {
+    #[always_inline]
+    fn always_inlined_increment(x: i32 as "int32_t") -> i32 as "int32_t" {
+        return x + 1;
+    }
+}
+
+#[test]
+fn always_inline() {
+    unsafe {
+        assert_eq!(always_inlined_increment(1), 2);
+    }
+}
+
+c! {
+    fn digest(data: &[u8] as ("const uint8_t *", "size_t")) -> [u8; 32] as "uint8_t" {
+        struct digest_result result;
+        size_t i;
+        for (i = 0; i < 32; i++) {
+            result.data[i] = (uint8_t)i;
+        }
+        for (i = 0; i < data_len; i++) {
+            result.data[i % 32] ^= data[i];
+        }
+        return result;
+    }
+}
+
+#[test]
+fn array_return() {
+    let mut expected = [0u8; 32];
+    for (i, byte) in expected.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    expected[0] ^= 0xff;
+    expected[1] ^= 0x01;
+
+    unsafe {
+        assert_eq!(digest(&[0xff, 0x01]), expected);
+    }
+}
+
+c! {
+    fn call_three_times(f: &Fn() as "rust_c_fn") {
+        RUST_C_CALL(f);
+        RUST_C_CALL(f);
+        RUST_C_CALL(f);
+    }
+
+    fn call_twice_mut(f: &mut FnMut() as "rust_c_fn") {
+        RUST_C_CALL(f);
+        RUST_C_CALL(f);
+    }
+}
+
+#[test]
+fn closure_argument() {
+    use std::cell::Cell;
+
+    let calls = Cell::new(0);
+    unsafe {
+        call_three_times(&|| calls.set(calls.get() + 1));
+    }
+    assert_eq!(calls.get(), 3);
+
+    let mut count = 0;
+    unsafe {
+        call_twice_mut(&mut || count += 1);
+    }
+    assert_eq!(count, 2);
+}
+
+c! {
+    fn ascii_uppercase(s: &mut str as ("char *", "size_t")) {
+        size_t i;
+        for (i = 0; i < s_len; i++) {
+            if (s[i] >= 'a' && s[i] <= 'z') {
+                s[i] -= 'a' - 'A';
+            }
+        }
+    }
+}
+
+#[test]
+fn mut_str_as_bytes() {
+    let mut s = String::from("Hello, w\u{f6}rld!");
+    unsafe {
+        ascii_uppercase(&mut s);
+    }
+    assert_eq!(s, "HELLO, W\u{f6}RLD!");
+}
+
+#[test]
+fn build_info() {
+    let info = include_str!(concat!(env!("OUT_DIR"), "/c_test_build_info.txt"));
+    assert!(info.contains("\ncompiler: "));
+    assert!(info.contains("c_test.c\n"));
+    if cfg!(any(target_arch = "x86", target_arch = "x86_64")) {
+        assert!(info.contains("\n    -msse4.1\n"));
+    }
+}
+
+// cc's own metadata is turned off, so rust-c has to tell cargo which of the
+// variables it reads to rerun for
+#[test]
+#[cfg(not(target_env = "msvc"))]
+fn rerun_if_env_changed() {
+    let output = include_str!(concat!(env!("OUT_DIR"), "/../output"));
+    assert!(output.contains("cargo:rerun-if-env-changed=CC\n"));
+    assert!(output.contains("cargo:rerun-if-env-changed=CFLAGS\n"));
+    assert!(output.contains("cargo:rerun-if-env-changed=TARGET_AR\n"));
+}
+
+c! {
+    fn sum_four(bytes: &[u8; 4] as "const uint8_t (*)[4]") -> u32 as "uint32_t" {
+        uint32_t sum = 0;
+        size_t i;
+        for (i = 0; i < sizeof(*bytes); i++) {
+            sum += (*bytes)[i];
+        }
+        return sum;
+    }
+}
+
+#[test]
+fn array_reference() {
+    let bytes = [1, 2, 3, 250];
+    assert_eq!(unsafe { sum_four(&bytes) }, 256);
+}
+
+c! {
+    fn answer_ptr() -> NonNull<u32> as "uint32_t *" {
+        static uint32_t answer = 42;
+        return &answer;
+    }
+
+    fn null_ptr() -> NonNull<u32> as "uint32_t *" {
+        return NULL;
+    }
+}
+
+#[test]
+fn non_null_return() {
+    assert_eq!(unsafe { *answer_ptr().as_ptr() }, 42);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "`null_ptr` returned a null pointer as a NonNull")]
+fn null_non_null_return() {
+    unsafe {
+        null_ptr();
+    }
+}
+
+c! {
+    raw {
+        int32_t c_test_square(int32_t x);
+    }
+
+    fn square_plus_one(x: i32 as "int32_t") -> i32 as "int32_t" {
+        return c_test_square(x) + 1;
+    }
+}
+
+#[test]
+fn link_order() {
+    assert_eq!(unsafe { square_plus_one(3) }, 10);
+}
+
+#[repr(C)]
+pub struct Pinned {
+    value: u32,
+    _pinned: std::marker::PhantomPinned,
+}
+
+c! {
+    raw {
+        struct pinned {
+            uint32_t value;
+        };
+    }
+
+    fn bump_pinned(pinned: Pin<&mut Pinned> as "struct pinned *") {
+        pinned->value += 1;
+    }
+}
+
+#[test]
+fn pinned_argument() {
+    let mut pinned = Box::pin(Pinned { value: 41, _pinned: std::marker::PhantomPinned });
+    let address = &*pinned as *const Pinned;
+    unsafe {
+        bump_pinned(pinned.as_mut());
+    }
+    assert_eq!(pinned.value, 42);
+    assert_eq!(&*pinned as *const Pinned, address);
+}
+
+c! {
+    fn point_buffer_size() -> usize as "size_t" {
+        unsigned char buffer[sizeof_Point];
+        return sizeof(buffer);
+    }
+
+    fn point_alignment() -> usize as "size_t" {
+        return alignof_Point;
+    }
+}
+
+#[test]
+fn size_and_align_constants() {
+    unsafe {
+        assert_eq!(point_buffer_size(), ::std::mem::size_of::<Point>());
+        assert_eq!(point_alignment(), ::std::mem::align_of::<Point>());
+    }
+}
+
+c! {
+    fn add_into(from: &u32 as "const uint32_t *", to: &mut u32 as "uint32_t *") {
+        *to += *from;
+    }
+}
+
+#[test]
+fn reference_qualification() {
+    let from = 2;
+    let mut to = 40;
+    unsafe {
+        add_into(&from, &mut to);
+    }
+    assert_eq!(to, 42);
+}
+
+c! {
+    #include "answer.h"
+
+    fn included_answer() -> i32 as "int32_t" {
+        return C_TEST_ANSWER;
+    }
+}
+
+#[test]
+fn included_header() {
+    assert_eq!(unsafe { included_answer() }, 42);
+}
+
+c! {
+    fn point_product(point: &Point as "const struct Point *") -> i64 as "int64_t" {
+        return point->x * point->y;
+    }
+}
+
+#[test]
+fn struct_reference() {
+    let point = Point { x: 6, y: 7 };
+    assert_eq!(unsafe { point_product(&point) }, 42);
+}
+
+c! {
+    #[derive(Debug, PartialEq)]
+    struct Range {
+        min: u32 as "uint32_t",
+        max: u32 as "uint32_t",
+    }
+
+    fn range_of(values: &[u32] as ("const uint32_t *", "size_t")) -> Range as "Range" {
+        Range range = { UINT32_MAX, 0 };
+        size_t i;
+        for (i = 0; i < values_len; i++) {
+            if (values[i] < range.min) {
+                range.min = values[i];
+            }
+            if (values[i] > range.max) {
+                range.max = values[i];
+            }
+        }
+        return range;
+    }
+}
+
+#[test]
+fn struct_return() {
+    assert_eq!(unsafe { range_of(&[4, 1, 9, 3]) }, Range { min: 1, max: 9 });
+}
+
+c! {
+    fn level_name_length(level: Level as "Level") -> usize as "size_t" {
+        switch (level) {
+        case Low:
+            return 3;
+        case High:
+            return 4;
+        default:
+            return 0;
+        }
+    }
+}
+
+#[test]
+fn enum_argument() {
+    unsafe {
+        assert_eq!(level_name_length(Level::Low), "low".len());
+        assert_eq!(level_name_length(Level::High), "high".len());
+    }
+}
+
+c! {
+    raw {
+        static rust_c_once registry_once = RUST_C_ONCE_INIT;
+        static uint32_t registry_inits;
+
+        static void init_registry(void) {
+            registry_inits++;
+        }
+    }
+
+    fn registry_init_count() -> u32 as "uint32_t" {
+        rust_c_call_once(&registry_once, init_registry);
+        return registry_inits;
+    }
+}
+
+#[test]
+fn call_once() {
+    let threads: Vec<_> = (0..8)
+        .map(|_| ::std::thread::spawn(|| unsafe { registry_init_count() }))
+        .collect();
+    for thread in threads {
+        assert_eq!(thread.join().unwrap(), 1);
+    }
+}
+
+c! {
+    fn hypotenuse(a: f64 as "double", b: f64 as "double") -> f64 as "double" {
+        return sqrt(a * a + b * b);
+    }
+
+    fn third(x: f32 as "float") -> f32 as "float" {
+        return x / 3.0f;
+    }
+}
+
+#[test]
+fn float_return() {
+    unsafe {
+        assert_eq!(hypotenuse(3.0, 4.0), (3.0f64 * 3.0 + 4.0 * 4.0).sqrt());
+        assert_eq!(third(1.0), 1.0f32 / 3.0);
+    }
+}
+
+c! {
+    fn maybe_write(out: Option<&mut u32> as "uint32_t *") -> bool as "bool_" {
+        if (out == NULL) {
+            return 0;
+        }
+        *out = 7;
+        return 1;
+    }
+}
+
+#[test]
+fn optional_mut_reference() {
+    let mut value = 0;
+    unsafe {
+        assert!(!maybe_write(None));
+        assert!(maybe_write(Some(&mut value)));
+    }
+    assert_eq!(value, 7);
+}
+
+c! {
+    #[derive(Debug, PartialEq)]
+    struct Interval {
+        start: u32 as "uint32_t",
+        end: u32 as "uint32_t",
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Rectangle {
+        x: Interval as "Interval",
+        y: Interval as "Interval",
+    }
+
+    fn same_rectangle(a: &Rectangle as "const Rectangle *", b: &Rectangle as "const Rectangle *") -> bool as "bool_" {
+        return rust_c_eq_Rectangle(a, b);
+    }
+}
+
+#[test]
+fn struct_equality() {
+    let a = Rectangle { x: Interval { start: 1, end: 2 }, y: Interval { start: 3, end: 4 } };
+    let b = Rectangle { x: Interval { start: 1, end: 2 }, y: Interval { start: 3, end: 5 } };
+    unsafe {
+        assert!(same_rectangle(&a, &a));
+        assert!(!same_rectangle(&a, &b));
+    }
+    assert!(a != b);
+}
+
+c! {
+    #[after_types]
+    #include "interval_pair.h"
+
+    fn pair_length(a: u32 as "uint32_t", b: u32 as "uint32_t") -> u32 as "uint32_t" {
+        IntervalPair pair = { { 0, 0 }, { 0, 0 } };
+        pair.first.end = a;
+        pair.second.end = b;
+        return pair.first.end + pair.second.end;
+    }
+}
+
+#[test]
+fn include_after_types() {
+    assert_eq!(unsafe { pair_length(3, 4) }, 7);
+}
+
+c! {
+    #include <stdio.h>
+    #include <string.h>
+
+    fn parse_digit(c: u8 as "uint8_t") -> Result<(), String> {
+        if (c < '0' || c > '9') {
+            snprintf(err, err_len, "'%c' is not a digit", c);
+            return 0;
+        }
+        return 1;
+    }
+
+    fn long_error() -> Result<(), String> {
+        memset(err, 'x', err_len);
+        return 0;
+    }
+}
+
+#[test]
+fn error_message() {
+    unsafe {
+        assert_eq!(parse_digit(b'7'), Ok(()));
+        assert_eq!(parse_digit(b'a'), Err("'a' is not a digit".to_owned()));
+        assert_eq!(long_error(), Err("x".repeat(256)));
+    }
+}
+
+c! {
+    fn push_squares(v: &mut Vec<u32> as "uint32_t", n: u32 as "uint32_t") {
+        uint32_t i;
+        for (i = 1; i <= n; i++) {
+            RUST_C_PUSH(v, i * i);
+        }
+    }
+}
+
+#[test]
+fn vec_push() {
+    let mut squares = vec![0];
+    unsafe { push_squares(&mut squares, 4) };
+    assert_eq!(squares, [0, 1, 4, 9, 16]);
+}
+
+c! {
+    fn ignore_argument(unused: u32 as "uint32_t") -> u32 as "uint32_t" {
+        return 5;
+    }
+}
+
+#[test]
+fn unused_argument() {
+    assert_eq!(unsafe { ignore_argument(7) }, 5);
+}
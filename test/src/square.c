@@ -0,0 +1,13 @@
+#include <stdint.h>
+
+int32_t c_test_square(int32_t x) {
+    return x * x;
+}
+
+/* The real definition of a c! function, which replaces its weak #[stub]. MSVC
+ * has no weak definitions, so the two would clash there. */
+#if !defined(_MSC_VER)
+int32_t stubbed_impl(int32_t x) {
+    return -x;
+}
+#endif
@@ -0,0 +1,5 @@
+#include <stdint.h>
+
+int32_t triple(int32_t x) {
+    return x * 3;
+}
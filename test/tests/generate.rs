@@ -0,0 +1,910 @@
+extern crate c;
+
+#[test]
+fn scalar_mapping() {
+    let code = c::generate(r#"
+        c! {
+            fn add(a: i32 as "int32_t", b: i32 as "int32_t") -> i32 as "int32_t" {
+                return a + b;
+            }
+        }
+    "#);
+
+    assert!(code.contains("int32_t add(int32_t a, int32_t b) RUST_C_NOEXCEPT;\n"));
+    assert!(code.contains("int32_t add(int32_t a, int32_t b) RUST_C_NOEXCEPT {"));
+}
+
+#[test]
+fn slice_mapping() {
+    let code = c::generate(r#"
+        c! {
+            fn sum(values: &[u32] as ("const uint32_t *", "size_t")) -> u32 as "uint32_t" {
+                return 0;
+            }
+        }
+    "#);
+
+    assert!(code.contains("uint32_t sum(const uint32_t * values, size_t values_len) RUST_C_NOEXCEPT;\n"));
+    assert!(code.contains(
+        "RUST_C_ASSERT(sum_values_elements_have_the_size_of_their_rust_type, \
+         sizeof(*(const uint32_t *)0) == 4);\n"));
+}
+
+#[test]
+fn void_mapping() {
+    let code = c::generate(r#"
+        c! {
+            fn nothing() {
+            }
+        }
+    "#);
+
+    assert!(code.contains("void nothing() RUST_C_NOEXCEPT;\n"));
+}
+
+#[test]
+fn never_mapping() {
+    let code = c::generate(r#"
+        c! {
+            fn stop() -> ! {
+                abort();
+            }
+        }
+    "#);
+
+    assert!(code.contains("RUST_C_NORETURN void stop() RUST_C_NOEXCEPT;\n"));
+}
+
+#[test]
+fn stub() {
+    let code = c::generate(r#"
+        c! {
+            #[stub]
+            fn unfinished(x: i32 as "int32_t") -> i32 as "int32_t" {
+                return not_written_yet(x);
+            }
+        }
+    "#);
+
+    assert!(code.contains("RUST_C_WEAK int32_t unfinished(int32_t x) RUST_C_NOEXCEPT {"));
+    assert!(code.contains(" abort(); "));
+    assert!(!code.contains("not_written_yet"));
+}
+
+#[test]
+fn always_inline() {
+    let code = c::generate(r#"
+        c! {
+            #[always_inline]
+            fn increment(x: i32 as "int32_t") -> i32 as "int32_t" {
+                return x + 1;
+            }
+        }
+    "#);
+
+    assert!(code.contains("int32_t increment(int32_t x) RUST_C_NOEXCEPT;\n"));
+    assert!(code.contains("RUST_C_ALWAYS_INLINE int32_t increment(int32_t x) RUST_C_NOEXCEPT {"));
+}
+
+#[test]
+fn array_return_mapping() {
+    let code = c::generate(r#"
+        c! {
+            fn zeroes() -> [u8; 16] as "uint8_t" {
+                struct zeroes_result result = { { 0 } };
+                return result;
+            }
+        }
+    "#);
+
+    assert!(code.contains("struct zeroes_result {\n    uint8_t data[16];\n};\n"));
+    assert!(code.contains("struct zeroes_result zeroes() RUST_C_NOEXCEPT;\n"));
+}
+
+#[test]
+fn closure_mapping() {
+    let code = c::generate(r#"
+        c! {
+            fn call(f: &Fn() as "rust_c_fn") {
+                RUST_C_CALL(f);
+            }
+        }
+    "#);
+
+    assert!(code.contains("void call(void *f, rust_c_thunk f_call) RUST_C_NOEXCEPT;\n"));
+}
+
+#[test]
+#[should_panic(expected = "`f` is a closure, whose C type must be \"rust_c_fn\"")]
+fn closure_not_rust_c_fn() {
+    c::generate(r#"
+        c! {
+            fn call(f: &Fn() as "void *") {
+            }
+        }
+    "#);
+}
+
+#[test]
+fn duplicate_includes() {
+    let code = c::generate(r#"
+        c! {
+            #include <stdio.h>
+            #include "rust_types.h"
+        }
+
+        c! {
+            #include < stdio.h >
+            #include <string.h>
+        }
+    "#);
+
+    assert_eq!(code.matches("#include <stdio.h>").count(), 1);
+    assert!(!code.contains("< stdio.h >"));
+    assert_eq!(code.matches("#include \"rust_types.h\"").count(), 1);
+    assert_eq!(code.matches("#include <string.h>").count(), 1);
+}
+
+#[test]
+fn enum_field() {
+    let code = c::generate(r#"
+        c! {
+            #[repr(u8)]
+            enum Kind {
+                Small,
+                Large,
+            }
+        }
+
+        c! {
+            struct Tagged {
+                kind: Kind as "Kind",
+                value: u32 as "uint32_t",
+            }
+        }
+    "#);
+
+    assert!(code.find("typedef uint8_t Kind;").unwrap() < code.find("struct Tagged {").unwrap());
+    assert!(code.contains("sizeof(((struct Tagged *)0)->kind) == sizeof(uint8_t)"));
+}
+
+#[test]
+fn doc_comments() {
+    let code = c::generate(r#"
+        c! {
+            /// A point on the screen.
+            ///
+            /// Measured in pixels.
+            struct Point {
+                x: i32 as "int32_t",
+                y: i32 as "int32_t",
+            }
+
+            #[doc = "The side of the screen."]
+            enum Side {
+                Left,
+                Right,
+            }
+        }
+    "#);
+
+    assert!(code.contains("/**\n * A point on the screen.\n *\n * Measured in pixels.\n */\n"));
+    assert!(code.find("Measured in pixels.").unwrap() < code.find("struct Point {").unwrap());
+    assert!(code.contains("/**\n * The side of the screen.\n */\n"));
+}
+
+#[test]
+fn disabled_feature() {
+    let code = c::generate(r#"
+        #[cfg(feature = "disabled")]
+        c! {
+            fn feature_gated() {}
+        }
+
+        c! {
+            fn always_generated() {}
+        }
+    "#);
+
+    assert!(!code.contains("feature_gated"));
+    assert!(code.contains("always_generated"));
+}
+
+#[test]
+fn aliasing_warnings() {
+    let warnings = c::warnings(r#"
+        c! {
+            fn float_bits(x: &[f32] as ("const uint32_t *", "size_t")) {}
+            fn float_bytes(x: &[f32] as ("const uint8_t *", "size_t")) {}
+            fn int_as_float(x: *const u32 as "const float *") {}
+            fn matching(x: &f64 as "const double *", y: &mut i32 as "int32_t *") {}
+        }
+    "#);
+
+    assert_eq!(warnings.len(), 2);
+    assert!(warnings[0].contains("`x` of the c! function `float_bits` points to floating point values"));
+    assert!(warnings[1].contains("`x` of the c! function `int_as_float` points to integer values"));
+}
+
+#[test]
+fn filled_buffer_mapping() {
+    let code = c::generate(r#"
+        c! {
+            fn fill(buf: &mut [u8] as ("uint8_t *", "size_t")) -> &[u8] in buf as "size_t" {
+                return 0;
+            }
+        }
+    "#);
+
+    assert!(code.contains("size_t fill(uint8_t * buf, size_t buf_len) RUST_C_NOEXCEPT;\n"));
+}
+
+#[test]
+#[should_panic(expected = "returns how much of it was written as \"size_t\", not \"int\"")]
+fn filled_buffer_count_not_size_t() {
+    c::generate(r#"
+        c! {
+            fn fill(buf: &mut [u8] as ("uint8_t *", "size_t")) -> &[u8] in buf as "int" {
+                return 0;
+            }
+        }
+    "#);
+}
+
+#[test]
+fn function_variant() {
+    let code = c::generate(r#"
+        c! {
+            #[variant(lanes_avx2 = "avx2")]
+            fn lanes() -> u32 as "uint32_t" {
+                return 4;
+            }
+        }
+    "#);
+
+    assert!(code.contains("uint32_t lanes() RUST_C_NOEXCEPT;\n"));
+    assert!(code.contains("uint32_t lanes_avx2() RUST_C_NOEXCEPT;\n"));
+    assert_eq!(code.matches("return 4;").count(), 2);
+}
+
+#[test]
+fn tuple_returning_variant() {
+    let code = c::generate(r#"
+        c! {
+            #[variant(bounds_avx2 = "avx2")]
+            fn bounds(values: &[u32] as ("const uint32_t *", "size_t")) -> (u32, u32) as ("uint32_t", "uint32_t") {
+                out->_0 = 0;
+                out->_1 = 0;
+            }
+        }
+    "#);
+
+    assert_eq!(code.matches("struct bounds_result {\n").count(), 1);
+    assert_eq!(code.matches("RUST_C_ASSERT(bounds_values_elements_have_the_size_of_their_rust_type").count(), 1);
+    assert!(code.contains("void bounds(const uint32_t * values, size_t values_len, struct bounds_result *out) RUST_C_NOEXCEPT;\n"));
+    assert!(code.contains(
+        "void bounds_avx2(const uint32_t * values, size_t values_len, struct bounds_result *out) RUST_C_NOEXCEPT;\n"));
+}
+
+#[test]
+#[should_panic(expected = "only tuples of 2 to 4 elements can be passed to C")]
+fn single_element_tuple_argument() {
+    c::generate(r#"
+        c! {
+            fn first(point: (i32,) as ("int32_t",)) -> i32 as "int32_t" {
+                return point_0;
+            }
+        }
+    "#);
+}
+
+#[test]
+#[should_panic(expected = "only tuples of 2 to 4 elements can be returned from C")]
+fn five_element_tuple_return() {
+    c::generate(r#"
+        c! {
+            fn five() -> (u8, u8, u8, u8, u8) as ("uint8_t", "uint8_t", "uint8_t", "uint8_t", "uint8_t") {
+            }
+        }
+    "#);
+}
+
+#[test]
+fn pointee_sizes() {
+    let code = c::generate(r#"
+        c! {
+            fn count(counter: &'static ::std::sync::atomic::AtomicU32 as "_Atomic uint32_t *",
+                     total: *mut u64 as "uint64_t *",
+                     opaque: *mut u8 as "void *") {}
+        }
+    "#);
+
+    assert!(code.contains(
+        "RUST_C_ASSERT(count_counter_points_to_its_rust_type, sizeof(*(_Atomic uint32_t *)0) == 4);\n"));
+    assert!(code.contains(
+        "RUST_C_ASSERT(count_total_points_to_its_rust_type, sizeof(*(uint64_t *)0) == 8);\n"));
+    assert!(!code.contains("count_opaque_points_to_its_rust_type"));
+}
+
+#[test]
+fn flags_enum() {
+    let code = c::generate(r#"
+        c! {
+            #[flags]
+            #[repr(u8)]
+            enum Access {
+                Read,
+                Write,
+            }
+        }
+    "#);
+
+    assert!(code.contains("enum {\n    Read = 1 << 0,\n    Write = 1 << 1\n};\ntypedef uint8_t Access;\n"));
+}
+
+#[test]
+fn flags_enum_filling_repr() {
+    let code = c::generate(r#"
+        c! {
+            #[flags]
+            #[repr(u8)]
+            enum Bits {
+                B0, B1, B2, B3, B4, B5, B6, B7,
+            }
+        }
+    "#);
+
+    assert!(code.contains("    B7 = 1 << 7\n};\n"));
+}
+
+#[test]
+#[should_panic(expected = "The #[flags] enum `Bits` has more than 7 variants")]
+fn flags_enum_wider_than_repr() {
+    c::generate(r#"
+        c! {
+            #[flags]
+            #[repr(i8)]
+            enum Bits {
+                B0, B1, B2, B3, B4, B5, B6, B7,
+            }
+        }
+    "#);
+}
+
+#[test]
+fn array_reference_mapping() {
+    let code = c::generate(r#"
+        c! {
+            fn sum_four(bytes: &[u8; 4] as "const uint8_t (*)[4]") -> u32 as "uint32_t" {
+                return 0;
+            }
+        }
+    "#);
+
+    assert!(code.contains("uint32_t sum_four(const uint8_t (*bytes)[4]) RUST_C_NOEXCEPT;\n"));
+    assert!(code.contains(
+        "RUST_C_ASSERT(sum_four_bytes_points_to_its_rust_type, sizeof(*(const uint8_t (*)[4])0) == (4) * 1);\n"));
+
+    let code = c::generate(r#"
+        c! {
+            fn first(bytes: &[u8; 4] as "const uint8_t *") -> u8 as "uint8_t" {
+                return bytes[0];
+            }
+        }
+    "#);
+
+    assert!(code.contains("uint8_t first(const uint8_t * bytes) RUST_C_NOEXCEPT;\n"));
+    assert!(code.contains(
+        "RUST_C_ASSERT(first_bytes_elements_have_the_size_of_their_rust_type, sizeof(*(const uint8_t *)0) == 1);\n"));
+}
+
+#[test]
+fn pinned_mapping() {
+    let code = c::generate(r#"
+        c! {
+            fn bump(value: Pin<&mut u32> as "uint32_t *") {}
+        }
+    "#);
+
+    assert!(code.contains("void bump(uint32_t * value) RUST_C_NOEXCEPT;\n"));
+    assert!(code.contains(
+        "RUST_C_ASSERT(bump_value_points_to_its_rust_type, sizeof(*(uint32_t *)0) == 4);\n"));
+}
+
+#[test]
+fn size_and_align_constants() {
+    let code = c::generate(r#"
+        c! {
+            #[repr(u8)]
+            enum Kind {
+                Small,
+            }
+
+            struct Point {
+                x: i32 as "int32_t",
+            }
+        }
+    "#);
+
+    assert!(code.contains("#define sizeof_Kind sizeof(Kind)\n#define alignof_Kind RUST_C_ALIGNOF(Kind)\n"));
+    assert!(code.contains(
+        "#define sizeof_Point sizeof(struct Point)\n#define alignof_Point RUST_C_ALIGNOF(struct Point)\n"));
+}
+
+#[test]
+fn reference_qualification() {
+    let code = c::generate(r#"
+        c! {
+            fn copy(from: &u32 as "const uint32_t *", to: &mut u32 as "uint32_t *") {
+                *to = *from;
+            }
+        }
+    "#);
+
+    assert!(code.contains("void copy(const uint32_t * from, uint32_t * to) RUST_C_NOEXCEPT;\n"));
+}
+
+#[test]
+#[should_panic(expected = "`value` is a shared reference, which C must not change what it points to")]
+fn shared_reference_to_mutable_pointer() {
+    c::generate(r#"
+        c! {
+            fn overwrite(value: &u32 as "uint32_t *") {
+                *value = 0;
+            }
+        }
+    "#);
+}
+
+#[test]
+fn struct_reference_mapping() {
+    let code = c::generate(r#"
+        c! {
+            struct Point {
+                x: i32 as "int32_t",
+            }
+        }
+
+        c! {
+            fn point_x(point: &Point as "const struct Point *") -> i32 as "int32_t" {
+                return point->x;
+            }
+        }
+    "#);
+
+    assert!(code.contains("int32_t point_x(const struct Point * point) RUST_C_NOEXCEPT;\n"));
+    assert!(code.contains(
+        "RUST_C_ASSERT(point_x_point_points_to_its_rust_type, \
+         sizeof(*(const struct Point *)0) == sizeof(struct Point));\n"));
+}
+
+#[test]
+fn struct_slice_mapping() {
+    let code = c::generate(r#"
+        c! {
+            struct Point {
+                x: i32 as "int32_t",
+            }
+        }
+
+        c! {
+            fn sum_x(points: &[Point] as ("const struct Point *", "size_t")) -> i32 as "int32_t" {
+                return 0;
+            }
+        }
+    "#);
+
+    assert!(code.contains(
+        "RUST_C_ASSERT(sum_x_points_elements_have_the_size_of_their_rust_type, \
+         sizeof(*(const struct Point *)0) == sizeof(struct Point));\n"));
+}
+
+#[test]
+fn struct_return_mapping() {
+    let code = c::generate(r#"
+        c! {
+            struct Range {
+                min: u32 as "uint32_t",
+                max: u32 as "uint32_t",
+            }
+
+            fn empty_range() -> Range as "Range" {
+                Range range = { 0, 0 };
+                return range;
+            }
+        }
+    "#);
+
+    assert!(code.contains("Range empty_range() RUST_C_NOEXCEPT;\n"));
+    assert!(code.contains("RUST_C_ASSERT(empty_range_returns_its_rust_type, sizeof(Range) == sizeof(struct Range));\n"));
+}
+
+#[test]
+fn assert_messages() {
+    let code = c::generate(r#"
+        c! {
+            fn first(values: &[u32] as ("const uint32_t *", "size_t")) {}
+        }
+    "#);
+
+    assert!(code.contains("#ifndef RUST_C_ASSERT_MESSAGE\n#define RUST_C_ASSERT_MESSAGE(name) \"rust-c: \" #name\n"));
+    assert!(code.contains("_Static_assert(condition, RUST_C_ASSERT_MESSAGE(name))"));
+    assert!(code.contains("RUST_C_ASSERT(first_values_elements_have_the_size_of_their_rust_type, "));
+}
+
+#[test]
+fn string_view_helper() {
+    let code = c::generate(r#"
+        c! {
+            fn find_comma(s: &str as ("const char *", "size_t")) -> usize as "size_t" {
+                return RUST_C_STRING_VIEW(s).find(',');
+            }
+        }
+    "#);
+
+    assert!(code.contains("#include <string_view>\n"));
+    assert!(code.contains("#define RUST_C_STRING_VIEW(s) (std::string_view(rust_c_chars(s), (s##_len)))\n"));
+    assert!(code.find("#include <string_view>").unwrap() < code.find("extern \"C\" {").unwrap());
+}
+
+#[test]
+fn enum_argument_mapping() {
+    let code = c::generate(r#"
+        c! {
+            #[repr(u16)]
+            enum Level {
+                Low,
+                High,
+            }
+
+            fn flip(level: Level as "Level") -> Level as "Level" {
+                return level == Low ? High : Low;
+            }
+        }
+    "#);
+
+    assert!(code.contains("Level flip(Level level) RUST_C_NOEXCEPT;\n"));
+    assert!(code.contains(
+        "RUST_C_ASSERT(flip_level_has_the_size_of_its_rust_type, sizeof(Level) == sizeof(uint16_t));\n"));
+}
+
+#[test]
+fn float_return_mapping() {
+    let code = c::generate(r#"
+        c! {
+            fn half(x: f64 as "double") -> f64 as "double" {
+                return x / 2;
+            }
+        }
+    "#);
+
+    assert!(code.contains("double half(double x) RUST_C_NOEXCEPT;\n"));
+    assert!(code.contains("RUST_C_ASSERT(half_returns_its_rust_type, sizeof(double) == 8);\n"));
+}
+
+#[test]
+#[should_panic(expected = "`half` returns floating point values, which C is given as `int64_t`")]
+fn float_returned_as_integer() {
+    c::generate(r#"
+        c! {
+            fn half(x: f64 as "double") -> f64 as "int64_t" {
+                return x / 2;
+            }
+        }
+    "#);
+}
+
+#[test]
+fn optional_reference_mapping() {
+    let code = c::generate(r#"
+        c! {
+            fn maybe_write(out: Option<&mut u32> as "uint32_t *", from: Option<&u64> as "const uint64_t *") {}
+        }
+    "#);
+
+    assert!(code.contains("void maybe_write(uint32_t * out, const uint64_t * from) RUST_C_NOEXCEPT;\n"));
+    assert!(code.contains("RUST_C_ASSERT(maybe_write_out_points_to_its_rust_type, sizeof(*(uint32_t *)0) == 4);\n"));
+    assert!(code.contains(
+        "RUST_C_ASSERT(maybe_write_from_points_to_its_rust_type, sizeof(*(const uint64_t *)0) == 8);\n"));
+}
+
+#[test]
+fn struct_equality() {
+    let code = c::generate(r#"
+        c! {
+            #[derive(PartialEq)]
+            struct Interval {
+                start: u32 as "uint32_t",
+                end: u32 as "uint32_t",
+            }
+
+            #[derive(Debug, PartialEq)]
+            struct Rectangle {
+                x: Interval as "Interval",
+                y: Interval as "Interval",
+            }
+
+            struct Unequal {
+                value: u32 as "uint32_t",
+            }
+        }
+    "#);
+
+    assert!(code.contains(
+        "RUST_C_INLINE int rust_c_eq_Interval(const struct Interval *a, const struct Interval *b)\n{\n    return 1\n        \
+         && a->start == b->start\n        && a->end == b->end;\n}\n"));
+    assert!(code.contains("\n        && rust_c_eq_Interval(&a->x, &b->x)\n        && rust_c_eq_Interval(&a->y, &b->y);\n"));
+    assert!(code.contains(
+        "inline bool operator==(const Rectangle &a, const Rectangle &b) { return rust_c_eq_Rectangle(&a, &b) != 0; }\n"));
+    assert!(!code.contains("rust_c_eq_Unequal"));
+}
+
+#[test]
+fn include_after_types() {
+    let code = c::generate(r#"
+        c! {
+            #include "before.h"
+            #[after_types]
+            #include "after.h"
+
+            struct Interval {
+                start: u32 as "uint32_t",
+                end: u32 as "uint32_t",
+            }
+        }
+    "#);
+
+    let before = code.find("#include \"before.h\"\n").unwrap();
+    let after = code.find("#include \"after.h\"\n").unwrap();
+    let types = code.find("struct Interval {\n").unwrap();
+    assert!(before < types);
+    assert!(types < after);
+}
+
+#[test]
+fn error_message_mapping() {
+    let code = c::generate(r#"
+        c! {
+            fn parse_digit(c: u8 as "uint8_t") -> Result<(), String> {
+                return 1;
+            }
+        }
+    "#);
+
+    assert!(code.contains("RUST_C_BOOL parse_digit(uint8_t c, char *err, size_t err_len) RUST_C_NOEXCEPT;\n"));
+}
+
+#[test]
+fn vec_push_mapping() {
+    let code = c::generate(r#"
+        c! {
+            fn push_squares(v: &mut Vec<u32> as "uint32_t", n: u32 as "uint32_t") {}
+        }
+    "#);
+
+    assert!(code.contains(
+        "void push_squares(void *v, void (*v_push)(void *, uint32_t), uint32_t n) RUST_C_NOEXCEPT;\n"));
+    assert!(code.contains(
+        "RUST_C_ASSERT(push_squares_v_elements_have_the_size_of_their_rust_type, sizeof(uint32_t) == 4);\n"));
+}
+
+#[test]
+fn narrowest_casts() {
+    let code = c::generate(r#"
+        c! {
+            fn count(s: &[u8] as ("const uint8_t *", "size_t")) -> usize as "size_t" {
+                return RUST_C_STRING_VIEW(s).size();
+            }
+        }
+    "#);
+
+    assert!(code.contains("inline const char *rust_c_chars(const char *s) { return s; }\n"));
+    assert!(code.contains(
+        "inline const char *rust_c_chars(const void *s) { return static_cast<const char *>(s); }\n"));
+    assert!(code.contains(
+        "inline const char *rust_c_chars(const unsigned char *s) { return reinterpret_cast<const char *>(s); }\n"));
+}
+
+#[test]
+fn unused_arguments() {
+    let code = c::generate(r#"
+        c! {
+            fn ignore(unused: u32 as "uint32_t", v: &mut Vec<u32> as "uint32_t") {}
+            fn nothing() {}
+        }
+    "#);
+
+    assert!(code.contains(
+        "RUST_C_NOEXCEPT { (void)unused; (void)v; (void)v_push; {"));
+    assert!(code.contains("void nothing() RUST_C_NOEXCEPT {}"));
+}
+
+#[test]
+fn test_cfg() {
+    let source = r#"
+        #[cfg(test)]
+        c! {
+            fn for_tests() {}
+        }
+
+        #[cfg(not(test))]
+        c! {
+            fn not_for_tests() {}
+        }
+
+        #[cfg(all(test, never))]
+        c! {
+            fn never_generated() {}
+        }
+
+        #[cfg(test)]
+        mod tests;
+    "#;
+    let code = c::generate(source);
+
+    assert!(code.contains("void for_tests() RUST_C_NOEXCEPT;\n"));
+    assert!(code.contains("void not_for_tests() RUST_C_NOEXCEPT;\n"));
+    assert!(!code.contains("never_generated"));
+
+    let warnings = c::warnings(source);
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].starts_with("rust-c can't read `mod tests;` at lib.rs:"));
+}
+
+#[test]
+#[should_panic(expected = "`RUST_C_ASSERT` is defined by rust-c, and may not be redefined in a c! block")]
+fn reserved_macro() {
+    c::generate(r#"
+        c! {
+            raw {
+                #define RUST_C_ASSERT(name, condition)
+            }
+        }
+    "#);
+}
+
+#[test]
+#[should_panic(expected = "`u8` is defined by rust-c, and may not be redefined in a c! block")]
+fn reserved_typedef() {
+    c::generate(r#"
+        c! {
+            raw {
+                typedef unsigned char u8;
+            }
+        }
+    "#);
+}
+
+#[test]
+fn unreserved_typedef() {
+    let code = c::generate(r#"
+        c! {
+            raw {
+                typedef struct { u8 u8_value; } byte_box;
+                typedef void (*byte_callback)(u8 value);
+            }
+        }
+    "#);
+
+    assert!(code.contains("typedef struct { u8 u8_value; } byte_box;"));
+}
+
+#[test]
+fn compiler_version_check() {
+    let code = c::Config::new()
+        .min_gcc_version(4, 7)
+        .min_clang_version(3, 1)
+        .generate("");
+
+    assert!(code.contains(
+        "#if defined(__clang__)\n\
+         #if __clang_major__ < 3 || (__clang_major__ == 3 && __clang_minor__ < 1)\n\
+         #error \"rust-c: this C code needs clang 3.1 or later\"\n\
+         #endif\n\
+         #elif defined(__GNUC__)\n\
+         #if __GNUC__ < 4 || (__GNUC__ == 4 && __GNUC_MINOR__ < 7)\n\
+         #error \"rust-c: this C code needs gcc 4.7 or later\"\n\
+         #endif\n\
+         #endif\n"));
+}
+
+#[test]
+fn no_compiler_version_check() {
+    assert!(!c::generate("").contains("#error"));
+}
+
+#[test]
+fn terse_asserts() {
+    let code = c::Config::new().assert_messages(false).generate("");
+
+    let terse = code.find("#define RUST_C_ASSERT_MESSAGE(name) \"\"\n").unwrap();
+    assert!(terse < code.find("#ifndef RUST_C_ASSERT_MESSAGE").unwrap());
+}
+
+#[test]
+fn custom_assert_message() {
+    let code = c::Config::new()
+        .header("#define RUST_C_ASSERT_MESSAGE(name) \"my-crate: \" #name")
+        .generate("");
+
+    let custom = code.find("#define RUST_C_ASSERT_MESSAGE(name) \"my-crate: \" #name\n").unwrap();
+    assert!(custom < code.find("#ifndef RUST_C_ASSERT_MESSAGE").unwrap());
+    assert!(!code.contains("#define RUST_C_ASSERT_MESSAGE(name) \"\"\n"));
+}
+
+#[test]
+fn deterministic_line_endings() {
+    let source = "\u{feff}c! {\r\n    raw {\r\n        #define ONE 1\r\n    }\r\n\r\n    \
+                  fn one() -> i32 as \"int32_t\" {\r\n        return ONE;\r\n    }\r\n}\r\n";
+    let code = c::generate(source);
+
+    assert_eq!(code, c::generate(source));
+    assert!(code.contains("return ONE;\n"));
+    assert!(!code.contains('\r'));
+    assert!(!code.contains('\u{feff}'));
+}
+
+#[test]
+fn field_offsets() {
+    let pair = |first: &str, second: &str| c::generate(&format!(r#"
+        c! {{
+            struct Pair {{
+                {}: u32 as "uint32_t",
+                {}: u32 as "uint32_t",
+            }}
+        }}
+    "#, first, second));
+
+    let in_order = "RUST_C_ASSERT(Pair_b_is_at_its_rust_offset, offsetof(struct Pair, b) == \
+                    RUST_C_ALIGN_UP(offsetof(struct Pair, a) + 4, RUST_C_ALIGNOF(uint32_t)));\n";
+    let swapped = "RUST_C_ASSERT(Pair_a_is_at_its_rust_offset, offsetof(struct Pair, a) == \
+                   RUST_C_ALIGN_UP(offsetof(struct Pair, b) + 4, RUST_C_ALIGNOF(uint32_t)));\n";
+
+    let code = pair("a", "b");
+    assert!(code.contains(in_order));
+    assert!(!code.contains(swapped));
+
+    let code = pair("b", "a");
+    assert!(code.contains(swapped));
+    assert!(!code.contains(in_order));
+}
+
+#[test]
+fn vec_return_mapping() {
+    let code = c::generate(r#"
+        c! {
+            fn squares(n: u32 as "uint32_t") -> Vec<u32 as "uint32_t"> {
+                return 0;
+            }
+        }
+    "#);
+
+    assert!(code.contains("uint32_t * squares(uint32_t n, size_t *out_len) RUST_C_NOEXCEPT;\n"));
+    assert!(code.contains("void rust_c_free_squares(void *ptr) RUST_C_NOEXCEPT;\n"));
+    assert!(code.contains("void rust_c_free_squares(void *ptr) RUST_C_NOEXCEPT { free(ptr); }\n"));
+}
+
+#[test]
+fn io_result_mapping() {
+    let code = c::generate(r#"
+        c! {
+            fn close_it(fd: i32 as "int") -> io::Result<()> {
+                return 0;
+            }
+        }
+    "#);
+
+    assert!(code.contains("int close_it(int fd) RUST_C_NOEXCEPT;\n"));
+}
+
+#[test]
+#[should_panic(expected = "`io::Result<u32>` can't be returned as an errno")]
+fn io_result_with_value() {
+    c::generate(r#"
+        c! {
+            fn read_it(fd: i32 as "int") -> io::Result<u32> {
+                return 0;
+            }
+        }
+    "#);
+}
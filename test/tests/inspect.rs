@@ -0,0 +1,52 @@
+extern crate c;
+
+use std::env;
+use std::fs;
+
+#[test]
+fn two_blocks() {
+    let dir = env::temp_dir().join("rust_c_inspect_two_blocks");
+    fs::create_dir_all(&dir).unwrap();
+    let src = dir.join("lib.rs");
+    fs::write(&src, r#"
+c! {
+    struct Point {
+        x: i32 as "int32_t",
+        y: i32 as "int32_t",
+    }
+
+    fn area(w: u32 as "uint32_t", h: u32 as "uint32_t") -> u32 as "uint32_t" {
+        return w * h;
+    }
+}
+
+c! {
+    fn sum(values: &[u32] as ("const uint32_t *", "size_t")) -> Result<u32 as "uint32_t", i32 as "int32_t"> {
+        return 0;
+    }
+}
+"#).unwrap();
+
+    let blocks = c::inspect(&src);
+
+    assert_eq!(blocks.functions.len(), 2);
+    assert_eq!(blocks.functions[0].name, "area");
+    assert_eq!(blocks.functions[0].return_type, "uint32_t");
+    assert_eq!(blocks.functions[0].parameters, vec!["uint32_t w", "uint32_t h"]);
+    assert!(blocks.functions[0].location.ends_with("lib.rs:8"));
+
+    assert_eq!(blocks.functions[1].name, "sum");
+    assert_eq!(blocks.functions[1].return_type, "RUST_C_BOOL");
+    assert_eq!(blocks.functions[1].parameters,
+               vec!["const uint32_t * values, size_t values_len", "uint32_t *out_ok", "int32_t *out_err"]);
+    assert!(blocks.functions[1].location.ends_with("lib.rs:14"));
+
+    assert_eq!(blocks.functions[0].declaration(), "uint32_t area(uint32_t w, uint32_t h)");
+    assert_eq!(blocks.functions[1].declaration(),
+               "RUST_C_BOOL sum(const uint32_t * values, size_t values_len, uint32_t *out_ok, int32_t *out_err)");
+
+    assert_eq!(blocks.types.len(), 1);
+    assert_eq!(blocks.types[0].name, "Point");
+    assert_eq!(blocks.types[0].kind, "struct");
+    assert!(blocks.types[0].location.ends_with("lib.rs:3"));
+}
@@ -0,0 +1,378 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::thread;
+use std::time::Duration;
+
+// A crate which builds its c! blocks with rust-c, written out from `files`,
+// each a path under the crate and its contents. The build script is given
+// `configure`, the body of the closure passed to `Config::build`.
+fn scratch(name: &str, configure: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = env::temp_dir().join(format!("rust_c_scratch_{}", name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("src")).unwrap();
+
+    let c = Path::new(env!("CARGO_MANIFEST_DIR")).parent().unwrap();
+    fs::write(dir.join("Cargo.toml"), format!(r#"
+[package]
+name = "{}"
+version = "0.1.0"
+build = "build.rs"
+
+[workspace]
+
+[build-dependencies]
+c = {{ path = {:?}, features = ["build"] }}
+
+[dependencies]
+c = {{ path = {:?}, features = ["macro"] }}
+"#, name, c, c)).unwrap();
+    build_script(&dir, name, configure);
+
+    for &(path, contents) in files {
+        fs::write(dir.join(path), contents).unwrap();
+    }
+    dir
+}
+
+fn build_script(dir: &Path, name: &str, configure: &str) {
+    fs::write(dir.join("build.rs"), format!(r#"
+extern crate c;
+
+fn main() {{
+    c::Config::new().build("src/main.rs", "{}", |cfg| {{
+        {}
+    }});
+}}
+"#, name, configure)).unwrap();
+}
+
+// Run cargo in a scratch crate, with `vars` added to its environment. They
+// share a target directory, so that rust-c and its dependencies are only
+// built once, unless given their own CARGO_TARGET_DIR.
+fn cargo(dir: &Path, args: &[&str], vars: &[(&str, &str)]) -> Output {
+    let mut command = Command::new(env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned()));
+    command.args(args)
+        .current_dir(dir)
+        .env("CARGO_TARGET_DIR", env::temp_dir().join("rust_c_scratch_target"));
+    for &(var, value) in vars {
+        command.env(var, value);
+    }
+    command.output().unwrap()
+}
+
+fn run(dir: &Path) -> String {
+    let output = cargo(dir, &["run", "--quiet"], &[]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+const VALUE_MAIN: &str = r#"
+#[macro_use]
+extern crate c;
+
+c! {
+    #include "value.h"
+
+    fn value() -> i32 as "int32_t" {
+        return VALUE;
+    }
+}
+
+fn main() {
+    println!("{}", unsafe { value() });
+}
+"#;
+
+#[test]
+fn header_change_rebuilds() {
+    let dir = scratch("header_change_rebuilds", r#"cfg.include("src");"#, &[
+        ("src/main.rs", VALUE_MAIN),
+        ("src/value.h", "#define VALUE 1\n"),
+    ]);
+    assert_eq!(run(&dir), "1\n");
+
+    fs::write(dir.join("src/value.h"), "#define VALUE 2\n").unwrap();
+    assert_eq!(run(&dir), "2\n");
+}
+
+// The library is archived again when only the archiver changes, which fails
+// if it doesn't exist
+#[test]
+#[cfg(not(target_env = "msvc"))]
+fn archiver_change_rebuilds() {
+    let dir = scratch("archiver_change_rebuilds", r#"cfg.include("src"); cfg.archiver("ar");"#, &[
+        ("src/main.rs", VALUE_MAIN),
+        ("src/value.h", "#define VALUE 1\n"),
+    ]);
+    assert_eq!(run(&dir), "1\n");
+
+    build_script(&dir, "archiver_change_rebuilds",
+                 r#"cfg.include("src"); cfg.archiver("rust-c-no-such-archiver");"#);
+    assert!(!cargo(&dir, &["build"], &[]).status.success());
+}
+
+// Some build systems run the build script of a crate more than once at
+// once, sharing its OUT_DIR, which this build script does by running copies
+// of itself
+#[test]
+fn concurrent_sessions() {
+    let dir = scratch("concurrent_sessions", r#"cfg.include("src");"#, &[
+        ("src/main.rs", VALUE_MAIN),
+        ("src/value.h", "#define VALUE 1\n"),
+    ]);
+    fs::write(dir.join("build.rs"), r#"
+extern crate c;
+
+use std::env;
+use std::process::{Command, Stdio};
+
+fn main() {
+    if env::var_os("SCRATCH_SESSION").is_some() {
+        c::Config::new().build("src/main.rs", "concurrent_sessions", |cfg| {
+            cfg.include("src");
+        });
+        return
+    }
+
+    let sessions: Vec<_> = (0..4)
+        .map(|_| {
+            Command::new(env::current_exe().unwrap())
+                .env("SCRATCH_SESSION", "1")
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    let mut metadata = String::new();
+    for session in sessions {
+        let output = session.wait_with_output().unwrap();
+        assert!(output.status.success());
+        metadata = String::from_utf8(output.stdout).unwrap();
+    }
+    print!("{}", metadata);
+}
+"#).unwrap();
+
+    assert_eq!(run(&dir), "1\n");
+}
+
+// A compiler which can't be run is reported plainly, before parsing the crate
+#[test]
+fn missing_compiler() {
+    let dir = scratch("missing_compiler", r#"cfg.include("src");"#, &[
+        ("src/main.rs", VALUE_MAIN),
+        ("src/value.h", "#define VALUE 1\n"),
+    ]);
+
+    let output = cargo(&dir, &["build"], &[("CC", "rust-c-no-such-compiler")]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Unable to run the C compiler `rust-c-no-such-compiler`"), "{}", stderr);
+    assert!(stderr.contains("set the CC environment variable"), "{}", stderr);
+}
+
+const OUT_OF_LINE_MAIN: &str = r#"
+#[macro_use]
+extern crate c;
+
+c! {
+    #[out_of_line]
+    fn one() -> i32 as "int32_t" {
+        return 1;
+    }
+
+    #[out_of_line]
+    fn two() -> i32 as "int32_t" {
+        return 2;
+    }
+}
+
+fn main() {
+    println!("{} {}", unsafe { one() }, unsafe { two() });
+}
+"#;
+
+// Each out-of-line function's library is only rebuilt when it changes
+#[test]
+#[cfg(not(target_env = "msvc"))]
+fn out_of_line_rebuilds() {
+    let dir = scratch("out_of_line_rebuilds", "let _ = cfg;", &[
+        ("src/main.rs", OUT_OF_LINE_MAIN),
+    ]);
+    assert_eq!(run(&dir), "1 2\n");
+
+    let build = env::temp_dir().join("rust_c_scratch_target/debug/build");
+    let archive = |id: &str| {
+        fs::read_dir(&build).unwrap()
+            .map(|entry| entry.unwrap().path().join(format!("out/libout_of_line_rebuilds_{}.a", id)))
+            .find(|archive| archive.exists())
+            .unwrap()
+    };
+    let modified = |id: &str| fs::metadata(archive(id)).unwrap().modified().unwrap();
+    let (one, two) = (modified("one"), modified("two"));
+
+    // Time stamps may only be to the second
+    thread::sleep(Duration::from_secs(1));
+    fs::write(dir.join("src/main.rs"), OUT_OF_LINE_MAIN.replace("return 1;", "return 3;")).unwrap();
+    assert_eq!(run(&dir), "3 2\n");
+    assert!(modified("one") != one);
+    assert_eq!(modified("two"), two);
+}
+
+// With the extension of a C++ file, the code is still compiled as C, in
+// which `class` is just a name. MSVC goes by the extension.
+#[test]
+#[cfg(not(target_env = "msvc"))]
+fn cc_extension() {
+    let dir = scratch("cc_extension", "let _ = cfg;", &[
+        ("src/main.rs", r#"
+#[macro_use]
+extern crate c;
+
+c! {
+    fn c_only() -> i32 as "int32_t" {
+        int32_t class = 1;
+        return class;
+    }
+}
+
+fn main() {
+    println!("{}", unsafe { c_only() });
+}
+"#),
+    ]);
+    fs::write(dir.join("build.rs"), r#"
+extern crate c;
+
+fn main() {
+    c::Config::new().extension("cc").build("src/main.rs", "cc_extension", |_| {});
+}
+"#).unwrap();
+    assert_eq!(run(&dir), "1\n");
+
+    let build = env::temp_dir().join("rust_c_scratch_target/debug/build");
+    assert!(fs::read_dir(&build).unwrap()
+        .any(|entry| entry.unwrap().path().join("out/cc_extension.cc").exists()));
+}
+
+// When the C code is only checked, there is no library to return
+#[test]
+fn syntax_only() {
+    let dir = scratch("syntax_only", "let _ = cfg;", &[
+        ("src/main.rs", r#"
+#[macro_use]
+extern crate c;
+
+c! {
+    fn one() -> i32 as "int32_t" {
+        return 1;
+    }
+}
+
+fn main() {
+    println!("{}", unsafe { one() });
+}
+"#),
+    ]);
+    fs::write(dir.join("build.rs"), r#"
+extern crate c;
+
+fn main() {
+    let library = c::Config::new().build("src/main.rs", "syntax_only", |_| {});
+    assert!(library.is_none());
+}
+"#).unwrap();
+
+    let output = cargo(&dir, &["check"], &[("RUST_C_SYNTAX_ONLY", "1")]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+// The C code links into a position-independent executable, hardened as
+// distributions build them
+#[test]
+#[cfg(target_os = "linux")]
+fn hardened_pie() {
+    let dir = scratch("hardened_pie", r#"cfg.include("src");"#, &[
+        ("src/main.rs", VALUE_MAIN),
+        ("src/value.h", "#define VALUE 1\n"),
+    ]);
+    let target_dir = env::temp_dir().join("rust_c_scratch_hardened_target");
+    let output = cargo(&dir, &["run", "--quiet", "--release"], &[
+        ("RUSTFLAGS", "-C relocation-model=pie -C link-arg=-pie -C link-arg=-Wl,-z,relro,-z,now"),
+        ("CFLAGS", "-fstack-protector-strong -D_FORTIFY_SOURCE=2"),
+        ("CARGO_TARGET_DIR", &target_dir.to_string_lossy()),
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(output.stdout, b"1\n");
+
+    // A position-independent executable is an ELF shared object
+    let binary = fs::read(target_dir.join("release/hardened_pie")).unwrap();
+    assert_eq!(binary[16], 3);
+}
+
+// A header can't be checked for the names rust-c reserves, so it is left to
+// the C compiler to report
+#[test]
+fn conflicting_header() {
+    let dir = scratch("conflicting_header", r#"cfg.include("src");"#, &[
+        ("src/main.rs", r#"
+#[macro_use]
+extern crate c;
+
+c! {
+    #include "bytes.h"
+
+    fn byte() -> u8 as "u8" {
+        return 1;
+    }
+}
+
+fn main() {
+    println!("{}", unsafe { byte() });
+}
+"#),
+        ("src/bytes.h", "typedef int u8;\n"),
+    ]);
+
+    let output = cargo(&dir, &["build"], &[]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    // gcc says "conflicting types for 'u8'", clang "typedef redefinition"
+    // and MSVC "'u8': redefinition", each at the typedef in the header
+    assert!(stderr.lines().any(|line| {
+        line.contains("bytes.h") &&
+            ((line.contains("conflicting types for") && line.contains("u8")) || line.contains("redefinition"))
+    }), "{}", stderr);
+}
+
+// Under `-C instrument-coverage`, clang is told to instrument the C code
+// alongside the rust, so the library has coverage mapping for it
+#[test]
+#[cfg(not(target_env = "msvc"))]
+fn coverage() {
+    if Command::new("clang").arg("--version").output().is_err() {
+        return
+    }
+    let dir = scratch("coverage", r#"cfg.include("src");"#, &[
+        ("src/main.rs", VALUE_MAIN),
+        ("src/value.h", "#define VALUE 1\n"),
+    ]);
+    let target_dir = env::temp_dir().join("rust_c_scratch_coverage_target");
+    let output = cargo(&dir, &["run", "--quiet"], &[
+        ("CC", "clang"),
+        ("RUSTFLAGS", "-C instrument-coverage"),
+        ("LLVM_PROFILE_FILE", &dir.join("coverage.profraw").to_string_lossy()),
+        ("CARGO_TARGET_DIR", &target_dir.to_string_lossy()),
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(output.stdout, b"1\n");
+
+    let archive = fs::read_dir(target_dir.join("debug/build")).unwrap()
+        .map(|entry| entry.unwrap().path().join("out/libcoverage.a"))
+        .find(|archive| archive.exists())
+        .unwrap();
+    let archive = fs::read(archive).unwrap();
+    assert!(archive.windows(b"__llvm_covmap".len()).any(|bytes| bytes == b"__llvm_covmap"));
+}